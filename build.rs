@@ -0,0 +1,105 @@
+// Generate size class table and the lookup table for small sizes, and build C++ operator new
+// Four classes in each doubling with 16 bytes quantum, up to 4GB
+// An 8 bytes tiny class is added when the minimum alignment is 8
+
+use std::env;
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::path::Path;
+
+// Operator new symbols defined by src/cxx.cc
+const CXX_NEW_SYMBOLS: &[&str] = &[
+    "_Znwm",
+    "_Znam",
+    "_ZnwmRKSt9nothrow_t",
+    "_ZnamRKSt9nothrow_t",
+    "_ZnwmSt11align_val_t",
+    "_ZnamSt11align_val_t",
+    "_ZnwmSt11align_val_tRKSt9nothrow_t",
+    "_ZnamSt11align_val_tRKSt9nothrow_t",
+];
+
+const QUANTUM: usize = 16;
+const MAXIMUM_CLASS_SIZE: usize = 1 << 32;
+const LOOKUP_MAX_SIZE: usize = 4096;
+const LOOKUP_GRANULE: usize = 8;
+
+fn tiny_class_sizes() -> Vec<usize> {
+    if env::var_os("CARGO_FEATURE_MIN_ALIGN_8").is_some() {
+        vec![8]
+    } else {
+        vec![]
+    }
+}
+
+fn class_sizes() -> Vec<usize> {
+    let mut sizes = tiny_class_sizes();
+    // quantum spaced classes in the first group
+    sizes.extend((1..=4).map(|i| i * QUANTUM));
+    let mut base = QUANTUM * 4;
+    while base < MAXIMUM_CLASS_SIZE {
+        let delta = base / 4;
+        sizes.extend((1..=4).map(|i| base + i * delta));
+        base *= 2;
+    }
+    sizes
+}
+
+// C++ operator new throws from C++ frames, see src/cxx.cc. Like the cxx module, 64-bit only
+// Symbols from static libraries are hidden in cdylib, exported by a version script of their own
+fn build_cxx_new() {
+    if env::var("CARGO_CFG_TARGET_POINTER_WIDTH").unwrap() != "64" {
+        return;
+    }
+    cc::Build::new()
+        .cpp(true)
+        .flag("-std=c++17")
+        .file("src/cxx.cc")
+        .compile("skyhooks_cxx");
+    if env::var("CARGO_CFG_TARGET_OS").unwrap() == "linux" {
+        let script = Path::new(&env::var("OUT_DIR").unwrap()).join("cxx.ver");
+        let symbols = CXX_NEW_SYMBOLS.join("; ");
+        fs::write(&script, format!("{{ global: {}; }};\n", symbols)).unwrap();
+        println!(
+            "cargo:rustc-cdylib-link-arg=-Wl,--version-script={}",
+            script.display()
+        );
+    }
+    println!("cargo:rerun-if-changed=src/cxx.cc");
+}
+
+fn main() {
+    build_cxx_new();
+    let sizes = class_sizes();
+    let mut out = String::new();
+    writeln!(out, "pub const NUM_SIZE_CLASS: usize = {};", sizes.len()).unwrap();
+    writeln!(
+        out,
+        "pub const NUM_TINY_SIZE_CLASS: usize = {};",
+        tiny_class_sizes().len()
+    )
+    .unwrap();
+    writeln!(out, "pub const QUANTUM: usize = {};", QUANTUM).unwrap();
+    writeln!(
+        out,
+        "pub static SIZE_CLASSES: [usize; NUM_SIZE_CLASS] = {:?};",
+        sizes
+    )
+    .unwrap();
+    // class index for sizes rounded up to granule, indexed by (size + GRANULE - 1) / GRANULE
+    let lookup = (0..=LOOKUP_MAX_SIZE / LOOKUP_GRANULE)
+        .map(|i| sizes.iter().position(|s| *s >= i * LOOKUP_GRANULE).unwrap())
+        .collect::<Vec<_>>();
+    writeln!(out, "const LOOKUP_MAX_SIZE: usize = {};", LOOKUP_MAX_SIZE).unwrap();
+    writeln!(out, "const LOOKUP_GRANULE: usize = {};", LOOKUP_GRANULE).unwrap();
+    writeln!(
+        out,
+        "static SIZE_CLASS_LOOKUP: [u8; {}] = {:?};",
+        lookup.len(),
+        lookup
+    )
+    .unwrap();
+    let path = Path::new(&env::var("OUT_DIR").unwrap()).join("size_classes.rs");
+    fs::write(path, out).unwrap();
+    println!("cargo:rerun-if-changed=build.rs");
+}
@@ -3,6 +3,9 @@ use crate::utils::*;
 use crate::{bump_heap, generic_heap, Ptr, Size, NULL_PTR, utils};
 use core::alloc::{GlobalAlloc, Layout};
 use core::cell::Cell;
+use core::cmp::min;
+use core::ptr;
+use errno::{set_errno, Errno};
 use lfmap::{Map, WordMap};
 use libc::*;
 use std::alloc::{Alloc, AllocErr};
@@ -11,16 +14,12 @@ use std::ptr::{null_mut, NonNull};
 thread_local! {
     pub static INNER_CALL: Cell<bool> = Cell::new(false);
 }
-lazy_static! {
-    static ref RUST_ADDR_MAPPING: lfmap::WordMap<MmapAllocator, AddressHasher> =
-        lfmap::WordMap::with_capacity(256);
-}
 
 pub unsafe fn nu_malloc(size: Size) -> Ptr {
     if size == 0 {
         return null_mut();
     } // The C standard (C17 7.22.3/1)
-    INNER_CALL.with(|is_inner| {
+    let res = INNER_CALL.with(|is_inner| {
         if !is_inner.get() {
             is_inner.set(true);
             let res = generic_heap::malloc(size);
@@ -30,33 +29,58 @@ pub unsafe fn nu_malloc(size: Size) -> Ptr {
             utils::log("BUMP MALLOC", size);
             bump_heap::malloc(size)
         }
-    })
+    });
+    if res == NULL_PTR {
+        set_errno(Errno(ENOMEM));
+    }
+    res
 }
 pub unsafe fn nu_free(ptr: Ptr) {
     if ptr == null_mut() {
         return;
     }
-    let is_inner = INNER_CALL.with(|is_inner| is_inner.get());
-    if !is_inner {
-        generic_heap::free(ptr);
-    } else {
-        utils::log("BUMP FREE", ptr as usize);
-        bump_heap::free(ptr);
+    INNER_CALL.with(|is_inner| {
+        if !is_inner.get() {
+            is_inner.set(true);
+            generic_heap::free(ptr);
+            is_inner.set(false);
+        } else {
+            utils::log("BUMP FREE", ptr as usize);
+            bump_heap::free(ptr);
+        }
+    });
+}
+
+// Free with size of the object known by the caller
+pub unsafe fn nu_free_sized(ptr: Ptr, size: Size) {
+    if ptr == null_mut() {
+        return;
     }
+    INNER_CALL.with(|is_inner| {
+        if !is_inner.get() {
+            is_inner.set(true);
+            generic_heap::free_sized(ptr, size);
+            is_inner.set(false);
+        } else {
+            bump_heap::free(ptr);
+        }
+    });
 }
 
 pub unsafe fn nu_calloc(nmemb: Size, size: Size) -> Ptr {
-    let total_size = nmemb * size;
-    let ptr = nu_malloc(total_size);
-    if ptr != NULL_PTR {
-        // zero-initialize is required
-        memset(ptr, 0, total_size);
-    }
-    ptr
+    let total_size = match nmemb.checked_mul(size) {
+        Some(s) => s,
+        None => {
+            set_errno(Errno(ENOMEM));
+            return null_mut();
+        }
+    };
+    // zero-initialize is required
+    nu_memalign_zeroed(1, total_size)
 }
 
 pub unsafe fn nu_realloc(ptr: Ptr, size: Size) -> Ptr {
-    INNER_CALL.with(|is_inner| {
+    let res = INNER_CALL.with(|is_inner| {
         if !is_inner.get() {
             is_inner.set(true);
             let res = generic_heap::realloc(ptr, size);
@@ -65,7 +89,107 @@ pub unsafe fn nu_realloc(ptr: Ptr, size: Size) -> Ptr {
         } else {
             bump_heap::realloc(ptr, size)
         }
-    })
+    });
+    if res == NULL_PTR && size != 0 {
+        set_errno(Errno(ENOMEM));
+    }
+    res
+}
+
+pub unsafe fn nu_reallocarray(ptr: Ptr, nmemb: Size, size: Size) -> Ptr {
+    match nmemb.checked_mul(size) {
+        Some(total_size) => nu_realloc(ptr, total_size),
+        None => {
+            set_errno(Errno(ENOMEM));
+            null_mut()
+        }
+    }
+}
+
+pub fn nu_usable_size(ptr: Ptr) -> Size {
+    if ptr == null_mut() {
+        return 0;
+    }
+    let is_inner = INNER_CALL.with(|is_inner| is_inner.get());
+    let size = if !is_inner {
+        generic_heap::usable_size(ptr)
+    } else {
+        bump_heap::usable_size(ptr)
+    };
+    size.unwrap_or(0)
+}
+
+// Allocate memory with address aligned to `align`, which must be a power of 2
+pub unsafe fn nu_memalign(align: Size, size: Size) -> Ptr {
+    debug_assert!(is_power_of_2(align));
+    if size == 0 {
+        return null_mut();
+    }
+    let res = INNER_CALL.with(|is_inner| {
+        if !is_inner.get() {
+            is_inner.set(true);
+            let res = generic_heap::malloc_aligned(size, align);
+            is_inner.set(false);
+            res
+        } else {
+            utils::log("BUMP MALLOC", size);
+            bump_heap::malloc_aligned(size, align)
+        }
+    });
+    if res == NULL_PTR {
+        set_errno(Errno(ENOMEM));
+    }
+    res
+}
+
+// Allocate memory aligned to `align` on NUMA `node`
+// EINVAL when the node does not exist, ENOMEM when out of memory
+pub unsafe fn nu_malloc_on_node(align: Size, size: Size, node: u16) -> Ptr {
+    debug_assert!(is_power_of_2(align));
+    if node >= *NUM_NUMA_NODES {
+        set_errno(Errno(EINVAL));
+        return null_mut();
+    }
+    if size == 0 {
+        return null_mut();
+    }
+    let res = INNER_CALL.with(|is_inner| {
+        if !is_inner.get() {
+            is_inner.set(true);
+            let res = generic_heap::malloc_on_node(size, align, node);
+            is_inner.set(false);
+            res
+        } else {
+            utils::log("BUMP MALLOC", size);
+            bump_heap::malloc_aligned(size, align)
+        }
+    });
+    if res == NULL_PTR {
+        set_errno(Errno(ENOMEM));
+    }
+    res
+}
+
+pub unsafe fn nu_memalign_zeroed(align: Size, size: Size) -> Ptr {
+    debug_assert!(is_power_of_2(align));
+    if size == 0 {
+        return null_mut();
+    }
+    let res = INNER_CALL.with(|is_inner| {
+        if !is_inner.get() {
+            is_inner.set(true);
+            let res = generic_heap::malloc_zeroed(size, align);
+            is_inner.set(false);
+            res
+        } else {
+            utils::log("BUMP MALLOC", size);
+            bump_heap::malloc_zeroed(size, align)
+        }
+    });
+    if res == NULL_PTR {
+        set_errno(Errno(ENOMEM));
+    }
+    res
 }
 
 // Allocator for rust itself for internal heaps
@@ -73,19 +197,31 @@ pub struct SkyhooksAllocator;
 
 unsafe impl GlobalAlloc for SkyhooksAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let size = layout.size();
-        let align = layout.align();
-        let actual_size = size + align - 1;
-        let base_addr = nu_malloc(actual_size) as usize;
-        let align_padding = align_padding(base_addr, align);
-        let rust_addr = base_addr + align_padding;
-        RUST_ADDR_MAPPING.insert(rust_addr, base_addr);
-        rust_addr as *mut u8
+        // null for out of memory, the caller will handle allocation error
+        nu_memalign(layout.align(), layout.size()) as *mut u8
     }
     unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
-        let addr = ptr as usize;
-        if let Some(base_addr) = RUST_ADDR_MAPPING.remove(addr) {
-            nu_free(base_addr as Ptr)
+        nu_free(ptr as Ptr)
+    }
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        nu_memalign_zeroed(layout.align(), layout.size()) as *mut u8
+    }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let align = layout.align();
+        if align <= MIN_ALIGN {
+            // malloc results are aligned to MIN_ALIGN
+            nu_realloc(ptr as Ptr, new_size) as *mut u8
+        } else if nu_usable_size(ptr as Ptr) >= new_size && ptr as usize % align == 0 {
+            // new size still fits the object
+            ptr
+        } else {
+            let new_layout = Layout::from_size_align_unchecked(new_size, align);
+            let new_ptr = self.alloc(new_layout);
+            if !new_ptr.is_null() {
+                ptr::copy_nonoverlapping(ptr, new_ptr, min(layout.size(), new_size));
+                self.dealloc(ptr, layout);
+            }
+            new_ptr
         }
     }
 }
@@ -98,10 +234,196 @@ impl Default for SkyhooksAllocator {
 
 unsafe impl Alloc for SkyhooksAllocator {
     unsafe fn alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
-        Ok(NonNull::new((self as &mut GlobalAlloc).alloc(layout)).unwrap())
+        NonNull::new((self as &mut GlobalAlloc).alloc(layout)).ok_or(AllocErr)
     }
 
     unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
         (self as &mut GlobalAlloc).dealloc(ptr.as_ptr(), layout)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::api::*;
+    use crate::{posix_memalign, Ptr, NULL_PTR};
+    use errno::errno;
+    use libc::{memset, EINVAL, ENOMEM};
+
+    #[test]
+    pub fn memalign() {
+        unsafe {
+            let mut align = 8;
+            while align <= 1 << 16 {
+                for size in &[1, 7, 64, 1000, 4096, 100000] {
+                    let ptr = nu_memalign(align, *size);
+                    assert_ne!(ptr, NULL_PTR);
+                    assert_eq!(ptr as usize % align, 0, "size {}, align {}", size, align);
+                    memset(ptr, 255, *size);
+                    nu_free(ptr);
+                }
+                align <<= 1;
+            }
+        }
+    }
+
+    #[test]
+    pub fn memalign_realloc() {
+        unsafe {
+            let ptr = nu_memalign(4096, 100) as *mut u8;
+            for i in 0..100 {
+                *ptr.add(i) = i as u8;
+            }
+            let new_ptr = nu_realloc(ptr as Ptr, 10000) as *mut u8;
+            for i in 0..100 {
+                assert_eq!(*new_ptr.add(i), i as u8);
+            }
+            nu_free(new_ptr as Ptr);
+        }
+    }
+
+    #[test]
+    pub fn aligned_realloc_in_place() {
+        unsafe {
+            let layout = Layout::from_size_align(1000, 64).unwrap();
+            let ptr = SkyhooksAllocator.alloc(layout);
+            let usable = nu_usable_size(ptr as Ptr);
+            assert!(usable >= 1000);
+            assert_eq!(SkyhooksAllocator.realloc(ptr, layout, usable), ptr);
+            assert_eq!(SkyhooksAllocator.realloc(ptr, layout, 900), ptr);
+            let new_layout = Layout::from_size_align(900, 64).unwrap();
+            let new_ptr = SkyhooksAllocator.realloc(ptr, new_layout, usable + 1);
+            assert_ne!(new_ptr, ptr);
+            assert_eq!(new_ptr as usize % 64, 0);
+            SkyhooksAllocator.dealloc(new_ptr, Layout::from_size_align(usable + 1, 64).unwrap());
+        }
+    }
+
+    #[test]
+    pub fn posix_memalign_errors() {
+        unsafe {
+            let mut ptr = NULL_PTR;
+            assert_eq!(posix_memalign(&mut ptr, 3, 16), EINVAL);
+            assert_eq!(posix_memalign(&mut ptr, 4, 16), EINVAL);
+            assert_eq!(posix_memalign(&mut ptr, 0, 16), EINVAL);
+            assert_eq!(posix_memalign(&mut ptr, 256, 16), 0);
+            assert_eq!(ptr as usize % 256, 0);
+            nu_free(ptr);
+        }
+    }
+
+    #[test]
+    pub fn min_align() {
+        unsafe {
+            for size in 1..=65536 {
+                let ptr = nu_malloc(size);
+                assert_eq!(ptr as usize % MIN_ALIGN, 0, "size {}", size);
+                nu_free(ptr);
+            }
+        }
+    }
+
+    #[test]
+    pub fn usable_size() {
+        unsafe {
+            assert_eq!(nu_usable_size(NULL_PTR), 0);
+            for size in &[1, 3, 9, 100, 1025, 65536, 65537, 1 << 20] {
+                let ptr = nu_malloc(*size);
+                let usable = nu_usable_size(ptr);
+                assert!(usable >= *size, "size {}, usable {}", size, usable);
+                memset(ptr, 255, usable);
+                nu_free(ptr);
+            }
+            let ptr = nu_memalign(4096, 100);
+            assert!(nu_usable_size(ptr) >= 100);
+            nu_free(ptr);
+        }
+    }
+
+    #[test]
+    pub fn overflow() {
+        unsafe {
+            assert_eq!(nu_calloc(usize::max_value() / 2, 3), NULL_PTR);
+            assert_eq!(errno().0, ENOMEM);
+            let ptr = nu_malloc(16);
+            assert_eq!(nu_reallocarray(ptr, usize::max_value(), 2), NULL_PTR);
+            assert_eq!(errno().0, ENOMEM);
+            // original object is untouched
+            let ptr = nu_reallocarray(ptr, 4, 8);
+            assert_ne!(ptr, NULL_PTR);
+            nu_free(ptr);
+            assert_eq!(nu_malloc(usize::max_value()), NULL_PTR);
+            assert_eq!(errno().0, ENOMEM);
+            assert_eq!(nu_malloc(usize::max_value() - 4096), NULL_PTR);
+            assert_eq!(errno().0, ENOMEM);
+        }
+    }
+
+    #[test]
+    pub fn rust_alignment() {
+        let allocator = SkyhooksAllocator;
+        unsafe {
+            let mut align = 1;
+            while align <= 1 << 16 {
+                for size in &[1, 24, 100, 4096, 65536, 100000] {
+                    let layout = Layout::from_size_align(*size, align).unwrap();
+                    let ptr = allocator.alloc(layout);
+                    assert_eq!(ptr as usize % align, 0, "size {}, align {}", size, align);
+                    memset(ptr as Ptr, 255, *size);
+                    allocator.dealloc(ptr, layout);
+                }
+                align <<= 1;
+            }
+        }
+    }
+
+    #[test]
+    pub fn rust_aligned_realloc() {
+        let allocator = SkyhooksAllocator;
+        unsafe {
+            for align in &[8, 32, 64, 256] {
+                let layout = Layout::from_size_align(24, *align).unwrap();
+                let mut ptr = allocator.alloc(layout);
+                let mut size = 24;
+                for new_size in &[80, 200, 1000, 70000, 100] {
+                    let old_layout = Layout::from_size_align(size, *align).unwrap();
+                    ptr = allocator.realloc(ptr, old_layout, *new_size);
+                    assert_eq!(ptr as usize % align, 0, "size {}", new_size);
+                    size = *new_size;
+                }
+                allocator.dealloc(ptr, Layout::from_size_align(size, *align).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    pub fn rust_zeroed_realloc() {
+        let allocator = SkyhooksAllocator;
+        unsafe {
+            for size in &[8, 100, 4096, 65536, 100000] {
+                let layout = Layout::from_size_align(*size, 8).unwrap();
+                // dirty objects for reuse
+                let ptr = allocator.alloc(layout);
+                memset(ptr as Ptr, 255, *size);
+                allocator.dealloc(ptr, layout);
+                let ptr = allocator.alloc_zeroed(layout);
+                for i in 0..*size {
+                    assert_eq!(*ptr.add(i), 0);
+                }
+                memset(ptr as Ptr, 1, *size);
+                for align in &[8, 256] {
+                    let layout = Layout::from_size_align(*size, *align).unwrap();
+                    let ptr = allocator.alloc(layout);
+                    memset(ptr as Ptr, 2, *size);
+                    let new_ptr = allocator.realloc(ptr, layout, size * 3);
+                    assert_eq!(new_ptr as usize % align, 0);
+                    for i in 0..*size {
+                        assert_eq!(*new_ptr.add(i), 2);
+                    }
+                    let new_layout = Layout::from_size_align(size * 3, *align).unwrap();
+                    allocator.dealloc(new_ptr, new_layout);
+                }
+                allocator.dealloc(ptr, layout);
+            }
+        }
+    }
+}
@@ -5,21 +5,28 @@
 // new address space will be allocated from the system
 
 use crate::collections::lflist;
-use crate::generic_heap::{size_class_index_from_size, NUM_SIZE_CLASS};
-use crate::mmap::{dealloc_regional, mmap_without_fd, munmap_memory};
+use crate::mmap::{bind_to_node, mmap_without_fd, munmap_memory, numa_policy};
 use crate::mmap_heap::*;
+use crate::scavenger::{release_pages, Advice, Decay};
+use crate::size_class::{size_class_index_from_size, size_class_size, NUM_SIZE_CLASS};
 use crate::utils::*;
-use crate::{Ptr, Size, NULL_PTR};
+use crate::{Ptr, Size, NULL, NULL_PTR};
 use core::alloc::{Alloc, AllocErr, GlobalAlloc, Layout};
 use core::sync::atomic::Ordering::Relaxed;
 use core::sync::atomic::{AtomicUsize, Ordering};
+use core::cmp::max;
 use core::{mem, ptr};
 use crossbeam::utils::Backoff;
 use lfmap::Map;
 use libc::*;
+use smallvec::SmallVec;
 use std::mem::MaybeUninit;
 
-const BUMP_SIZE_CLASS: usize = NUM_SIZE_CLASS << 1;
+const BUMP_SIZE_CLASS: usize = NUM_SIZE_CLASS;
+
+// Decay stages of free objects spanning whole pages, kept in low bits of the free time
+const PAGES_DIRTY: usize = 1;
+const PAGES_MUZZY: usize = 2;
 
 type SizeClasses<A: Alloc + Default> = [SizeClass<A>; BUMP_SIZE_CLASS];
 
@@ -27,14 +34,21 @@ lazy_static! {
     static ref ALLOC_INNER: AllocatorInstance<MmapAllocator> = AllocatorInstance::new();
     static ref MALLOC_SIZE: lfmap::WordMap<MmapAllocator, AddressHasher> =
         lfmap::WordMap::<MmapAllocator, AddressHasher>::with_capacity(256);
-    static ref MAXIMUM_FREE_LIST_COVERED_SIZE: usize = maximum_free_list_covered_size();
+    // Alignment of objects from malloc aligned larger than cache line
+    static ref MALLOC_ALIGN: lfmap::WordMap<MmapAllocator, AddressHasher> =
+        lfmap::WordMap::<MmapAllocator, AddressHasher>::with_capacity(64);
 }
 
 pub struct AllocatorInstance<A: Alloc + Default> {
     tail: AtomicUsize,
     base: AtomicUsize,
     address_map: lfmap::WordMap<A, AddressHasher>,
+    // Free time and decay stage of free objects spanning whole pages, by origin address
+    // Objects with pages dropped by MADV_DONTNEED are not tracked
+    free_pages: lfmap::WordMap<A, AddressHasher>,
     sizes: SizeClasses<A>,
+    // NUMA node address spaces of node-local heaps are placed on
+    node: Option<u16>,
 }
 
 struct SizeClass<A: Alloc + Default> {
@@ -44,8 +58,14 @@ struct SizeClass<A: Alloc + Default> {
 
 pub const HEAP_VIRT_SIZE: usize = 128 * 1024 * 1024; // 128MB
 
-fn allocate_address_space() -> Ptr {
-    mmap_without_fd(HEAP_VIRT_SIZE)
+// Returns null pointer when out of memory
+// Address space of node-local heaps follows the NUMA policy, before any page is touched
+fn allocate_address_space(node: Option<u16>) -> Ptr {
+    let ptr = mmap_without_fd(HEAP_VIRT_SIZE);
+    if let (Some(node), false) = (node, ptr == NULL_PTR) {
+        bind_to_node(ptr, HEAP_VIRT_SIZE, node, numa_policy());
+    }
+    ptr
 }
 
 // dealloc address space only been used when CAS base failed
@@ -56,62 +76,171 @@ fn dealloc_address_space(address: Ptr) {
 
 impl<A: Alloc + Default> AllocatorInstance<A> {
     pub fn new() -> Self {
-        let addr = allocate_address_space();
+        Self::with_node(None)
+    }
+
+    // Heap with memory from `node`
+    pub fn on_node(node: u16) -> Self {
+        Self::with_node(Some(node))
+    }
+
+    fn with_node(node: Option<u16>) -> Self {
+        let addr = allocate_address_space(node);
         Self {
             base: AtomicUsize::new(addr as usize),
             tail: AtomicUsize::new(addr as usize),
             address_map: lfmap::WordMap::with_capacity(4096),
+            free_pages: lfmap::WordMap::with_capacity(64),
             sizes: size_classes(),
+            node,
         }
     }
 
+    // Returns NULL when the size cannot fit in an address space or the system is out of memory
     pub fn bump_allocate(&self, size: usize) -> usize {
+        self.bump_allocate_aligned(size, 1)
+    }
+
+    // Space skipped for alignment is never used
+    pub fn bump_allocate_aligned(&self, size: usize, align: usize) -> usize {
         let backoff = Backoff::new();
+        if size.saturating_add(align - 1) > HEAP_VIRT_SIZE {
+            return NULL;
+        }
         loop {
             let base = self.base.load(Relaxed);
             let current_tail = self.tail.load(Relaxed);
-            let new_tail = current_tail + size;
+            let start = current_tail + align_padding(current_tail, align);
+            let new_tail = start + size;
             let upper_bound = base + HEAP_VIRT_SIZE;
-            if current_tail < base || current_tail > upper_bound {
+            if base == NULL {
+                // no address space yet for previous failure
+                if !self.swap_memory(base) {
+                    return NULL;
+                }
+            } else if current_tail < base || current_tail > upper_bound {
                 // current out of range, wrong memory target
             } else if new_tail > upper_bound {
                 // may overflow the address space, need to allocate another address space
                 // Fetch the old base address for reference in CAS
-                self.swap_memory(base);
+                if !self.swap_memory(base) {
+                    return NULL;
+                }
             // Anyhow, skip follow statements and retry
             } else if self
                 .tail
                 .compare_and_swap(current_tail, new_tail, Ordering::SeqCst)
                 == current_tail
             {
-                debug_assert!(current_tail > 0);
-                debug_assert!(current_tail >= base);
-                debug_assert!(current_tail < base + HEAP_VIRT_SIZE);
-                debug_validate(current_tail as Ptr, size);
-                return current_tail;
+                debug_assert!(start > 0);
+                debug_assert!(start >= base);
+                debug_assert!(start < base + HEAP_VIRT_SIZE);
+                debug_validate(start as Ptr, size);
+                return start;
             }
             // CAS tail failed, retry
         }
     }
 
+    // Sizes beyond all size classes cannot fit in an address space, bump allocation fails for them
     fn size_of_object(&self, layout: &Layout) -> (usize, usize) {
         let align = layout.align();
         let size = layout.size();
         let mut actual_size = actual_size_of(align, size);
         let size_class_index = size_class_index_from_size(actual_size);
-        if size_class_index < self.sizes.len() {
-            let size_class_size = self.sizes[size_class_index].size;
-            debug_assert!(size_class_size >= actual_size);
-            actual_size = size_class_size;
-        } else {
-            actual_size = actual_size + align_padding(actual_size, *SYS_PAGE_SIZE);
-            debug!("allocate large {}", actual_size);
+        if let Some(size_class) = self.sizes.get(size_class_index) {
+            debug_assert!(size_class.size >= actual_size);
+            actual_size = size_class.size;
         }
         (actual_size, size_class_index)
     }
 
-    fn swap_memory(&self, old_base: usize) {
-        let new_base = allocate_address_space();
+    // Extend object at the tail of current address space by bumping the tail
+    pub fn try_extend(&self, ptr: *mut u8, old_layout: Layout, new_layout: Layout) -> bool {
+        let addr = ptr as usize;
+        let origin_addr = match self.address_map.get(addr) {
+            Some(addr) => addr,
+            None => return false,
+        };
+        let (old_actual_size, _) = self.size_of_object(&old_layout);
+        let (new_actual_size, _) = self.size_of_object(&new_layout);
+        let base = self.base.load(Relaxed);
+        let old_tail = origin_addr + old_actual_size;
+        let new_tail = origin_addr + new_actual_size;
+        if origin_addr < base || new_tail > base + HEAP_VIRT_SIZE {
+            return false;
+        }
+        self.tail
+            .compare_and_swap(old_tail, new_tail, Ordering::SeqCst)
+            == old_tail
+    }
+
+    // Whether the object is allocated from this heap, addresses of heaps never overlap
+    pub fn contains(&self, ptr: *mut u8) -> bool {
+        self.address_map.get(ptr as usize).is_some()
+    }
+
+    // Usable size of an object allocated by `layout`, including padding in its size class
+    pub fn usable_size(&self, ptr: *mut u8, layout: Layout) -> Option<usize> {
+        let addr = ptr as usize;
+        self.address_map.get(addr).map(|origin_addr| {
+            let (actual_size, _) = self.size_of_object(&layout);
+            actual_size - (addr - origin_addr)
+        })
+    }
+
+    // Release pages of free objects in free lists when their decay is due
+    // Returns number of bytes released, pages released by MADV_FREE before are not counted again
+    // Free lists are drained during the walk so the objects cannot be reused while releasing
+    pub fn scavenge(&self, now: u64, decay: Decay) -> usize {
+        let page_size = *SYS_PAGE_SIZE;
+        let mut released = 0;
+        for size_class in self.sizes.iter().filter(|sc| sc.size >= page_size) {
+            if size_class.free_list.count() == 0 {
+                continue;
+            }
+            let mut objects = SmallVec::<[usize; 64]>::new();
+            size_class
+                .free_list
+                .drop_out_all(Some(|(addr, _)| objects.push(addr)));
+            for addr in objects {
+                released += self.release_free_object(addr, size_class.size, now, decay);
+                size_class.free_list.push(addr);
+            }
+        }
+        released
+    }
+
+    fn release_free_object(&self, addr: usize, size: usize, now: u64, decay: Decay) -> usize {
+        let record = match self.free_pages.get(addr) {
+            Some(record) => record,
+            None => return 0,
+        };
+        let muzzy = record & PAGES_MUZZY != 0;
+        let elapsed = now.saturating_sub((record >> 2) as u64);
+        let advice = match decay.advice(muzzy, elapsed) {
+            Some(advice) => advice,
+            None => return 0,
+        };
+        let released = release_pages(addr, size, advice);
+        self.free_pages.remove(addr);
+        if advice == Advice::Free {
+            self.free_pages
+                .insert(addr, (now as usize) << 2 | PAGES_MUZZY);
+        }
+        if muzzy {
+            0
+        } else {
+            released
+        }
+    }
+
+    // Returns false if the system cannot provide new address space
+    fn swap_memory(&self, old_base: usize) -> bool {
+        let new_base = allocate_address_space(self.node);
+        if new_base == NULL_PTR {
+            return false;
+        }
         if self
             .base
             .compare_and_swap(old_base, new_base as usize, Ordering::Relaxed)
@@ -124,45 +253,66 @@ impl<A: Alloc + Default> AllocatorInstance<A> {
             // update tail by store. This will fail all ongoing allocation and retry
             self.tail.store(new_base as usize, Ordering::SeqCst);
         }
+        true
     }
 }
 
-unsafe impl<A: Alloc + Default> GlobalAlloc for AllocatorInstance<A> {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+impl<A: Alloc + Default> AllocatorInstance<A> {
+    // Returns the object address and whether it is never used, which is still zeroed from mmap
+    unsafe fn alloc_object(&self, layout: Layout) -> (*mut u8, bool) {
         let align = layout.align();
         let (actual_size, size_class_index) = self.size_of_object(&layout);
-        let origin_addr = self
+        let (origin_addr, fresh) = self
             .sizes
             .get(size_class_index)
             .and_then(|sc| sc.free_list.pop())
-            .unwrap_or_else(|| self.bump_allocate(actual_size));
+            .map(|addr| (addr, false))
+            .unwrap_or_else(|| (self.bump_allocate(actual_size), true));
+        if origin_addr == NULL {
+            return (ptr::null_mut(), false);
+        }
+        if !fresh && actual_size >= *SYS_PAGE_SIZE {
+            self.free_pages.remove(origin_addr);
+        }
         let align_padding = align_padding(origin_addr, align);
         let final_addr = origin_addr + align_padding;
         self.address_map.insert(final_addr, origin_addr);
         debug_validate(final_addr as Ptr, actual_size);
-        return final_addr as *mut u8;
+        return (final_addr as *mut u8, fresh);
+    }
+}
+
+unsafe impl<A: Alloc + Default> GlobalAlloc for AllocatorInstance<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.alloc_object(layout).0
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let (ptr, fresh) = self.alloc_object(layout);
+        if !fresh && !ptr.is_null() {
+            ptr::write_bytes(ptr, 0, layout.size());
+        }
+        ptr
     }
 
+    // Objects allocated always have a size class, see `size_of_object`
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         let (actual_size, size_class_index) = self.size_of_object(&layout);
         let addr = ptr as usize;
         if let Some(actual_addr) = self.address_map.get(addr) {
-            let size_class_index = size_class_index_from_size(actual_size);
-            if size_class_index < self.sizes.len() {
-                debug_validate(ptr as Ptr, actual_size);
-                self.sizes[size_class_index].free_list.push(actual_addr);
-            } else {
-                // this may be a problem
-                self.address_map.remove(addr);
-                dealloc_regional(actual_addr as Ptr, actual_size);
+            debug_validate(ptr as Ptr, actual_size);
+            if actual_size >= *SYS_PAGE_SIZE {
+                self.free_pages
+                    .insert(actual_addr, (monotonic_ms() as usize) << 2 | PAGES_DIRTY);
             }
+            self.sizes[size_class_index].free_list.push(actual_addr);
         }
     }
 }
 
 unsafe impl Alloc for BumpAllocator {
     unsafe fn alloc(&mut self, layout: Layout) -> Result<ptr::NonNull<u8>, AllocErr> {
-        Ok(ptr::NonNull::new(ALLOC_INNER.alloc(layout)).unwrap())
+        ptr::NonNull::new(ALLOC_INNER.alloc(layout)).ok_or(AllocErr)
     }
 
     unsafe fn dealloc(&mut self, ptr: ptr::NonNull<u8>, layout: Layout) {
@@ -186,11 +336,20 @@ unsafe impl GlobalAlloc for BumpAllocator {
         ALLOC_INNER.alloc(layout)
     }
 
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        ALLOC_INNER.alloc_zeroed(layout)
+    }
+
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         ALLOC_INNER.dealloc(ptr, layout)
     }
 }
 
+// Heap behind `BumpAllocator`, large heap places objects in it and keeps its own records
+pub fn general_allocator() -> &'static AllocatorInstance<MmapAllocator> {
+    &ALLOC_INNER
+}
+
 impl Default for BumpAllocator {
     fn default() -> Self {
         Self
@@ -198,14 +357,40 @@ impl Default for BumpAllocator {
 }
 
 pub unsafe fn malloc(size: Size) -> Ptr {
-    let layout = Layout::from_size_align(size, CACHE_LINE_SIZE).unwrap();
-    let ptr = BumpAllocator.alloc(layout) as Ptr;
-    MALLOC_SIZE.insert(ptr as usize, size as usize);
+    malloc_aligned(size, CACHE_LINE_SIZE)
+}
+pub unsafe fn malloc_aligned(size: Size, align: Size) -> Ptr {
+    malloc_object(size, align, false)
+}
+pub unsafe fn malloc_zeroed(size: Size, align: Size) -> Ptr {
+    malloc_object(size, align, true)
+}
+
+// Cache line alignment covers MIN_ALIGN
+unsafe fn malloc_object(size: Size, align: Size, zeroed: bool) -> Ptr {
+    let align = max(align, CACHE_LINE_SIZE);
+    debug_assert!(align >= MIN_ALIGN);
+    let layout = match Layout::from_size_align(size, align) {
+        Ok(layout) => layout,
+        Err(_) => return NULL_PTR,
+    };
+    let ptr = if zeroed {
+        BumpAllocator.alloc_zeroed(layout) as Ptr
+    } else {
+        BumpAllocator.alloc(layout) as Ptr
+    };
+    if ptr != NULL_PTR {
+        MALLOC_SIZE.insert(ptr as usize, size as usize);
+        if align > CACHE_LINE_SIZE {
+            MALLOC_ALIGN.insert(ptr as usize, align);
+        }
+    }
     ptr
 }
 pub unsafe fn free(ptr: Ptr) -> bool {
     if let Some(size) = MALLOC_SIZE.remove(ptr as usize) {
-        let layout = Layout::from_size_align(size, CACHE_LINE_SIZE).unwrap();
+        let layout = malloc_layout(ptr, size);
+        MALLOC_ALIGN.remove(ptr as usize);
         BumpAllocator.dealloc(ptr as *mut u8, layout);
         true
     } else {
@@ -213,13 +398,22 @@ pub unsafe fn free(ptr: Ptr) -> bool {
     }
 }
 
+// Release free pages of the bump heap for general allocation, returns number of bytes released
+pub fn scavenge(now: u64, decay: Decay) -> usize {
+    ALLOC_INNER.scavenge(now, decay)
+}
+
+#[inline]
+fn malloc_layout(ptr: Ptr, size: Size) -> Layout {
+    let align = MALLOC_ALIGN.get(ptr as usize).unwrap_or(CACHE_LINE_SIZE);
+    Layout::from_size_align(size, align).unwrap()
+}
+
 fn size_classes<A: Alloc + Default>() -> SizeClasses<A> {
     let mut data: [MaybeUninit<SizeClass<A>>; BUMP_SIZE_CLASS] =
         unsafe { MaybeUninit::uninit().assume_init() };
-    let mut size = 2;
-    for elem in &mut data[..] {
-        *elem = MaybeUninit::new(SizeClass::new(size));
-        size *= 2;
+    for (index, elem) in data.iter_mut().enumerate() {
+        *elem = MaybeUninit::new(SizeClass::new(size_class_size(index)));
     }
     unsafe { mem::transmute::<_, SizeClasses<A>>(data) }
 }
@@ -229,9 +423,31 @@ pub fn size_of(ptr: Ptr) -> Option<usize> {
     MALLOC_SIZE.get(ptr as usize)
 }
 
-#[inline]
-fn maximum_free_list_covered_size() -> usize {
-    2 << (BUMP_SIZE_CLASS - 1)
+pub unsafe fn try_extend(ptr: Ptr, size: Size) -> bool {
+    let addr = ptr as usize;
+    let old_size = match MALLOC_SIZE.get(addr) {
+        Some(size) => size,
+        None => return false,
+    };
+    let old_layout = malloc_layout(ptr, old_size);
+    let new_layout = match Layout::from_size_align(size, old_layout.align()) {
+        Ok(layout) => layout,
+        Err(_) => return false,
+    };
+    if ALLOC_INNER.try_extend(ptr as *mut u8, old_layout, new_layout) {
+        MALLOC_SIZE.remove(addr);
+        MALLOC_SIZE.insert(addr, size);
+        true
+    } else {
+        false
+    }
+}
+
+pub fn usable_size(ptr: Ptr) -> Option<usize> {
+    MALLOC_SIZE.get(ptr as usize).and_then(|size| {
+        let layout = malloc_layout(ptr, size);
+        ALLOC_INNER.usable_size(ptr as *mut u8, layout)
+    })
 }
 
 #[inline]
@@ -258,6 +474,10 @@ pub unsafe fn realloc(ptr: Ptr, size: Size) -> Ptr {
         return ptr;
     }
     let new_ptr = malloc(size);
+    if new_ptr == NULL_PTR {
+        // original object is untouched
+        return NULL_PTR;
+    }
     memcpy(new_ptr, ptr, old_size);
     free(ptr);
     new_ptr
@@ -265,8 +485,9 @@ pub unsafe fn realloc(ptr: Ptr, size: Size) -> Ptr {
 
 #[cfg(test)]
 mod test {
-    use crate::bump_heap::BumpAllocator;
-    use crate::utils::AddressHasher;
+    use crate::bump_heap::{AllocatorInstance, BumpAllocator};
+    use crate::mmap::{memory_policy_of, node_of_page};
+    use crate::utils::{AddressHasher, SYS_PAGE_SIZE};
     use crate::Ptr;
     use lfmap::Map;
     use std::alloc::{GlobalAlloc, Layout};
@@ -321,4 +542,29 @@ mod test {
             assert_eq!(map.remove(i), Some(i * 2), "index: {}", i);
         }
     }
+
+    #[test]
+    pub fn node_heap_placement() {
+        let heap = AllocatorInstance::<BumpAllocator>::on_node(0);
+        let size = *SYS_PAGE_SIZE * 4;
+        let ptr = heap.bump_allocate(size) as Ptr;
+        assert!(!ptr.is_null());
+        let (mode, nodes) = match memory_policy_of(ptr) {
+            Some(policy) => policy,
+            None => {
+                println!("NUMA policy is not supported");
+                return;
+            }
+        };
+        // default mode when the policy is first touch
+        if mode != 0 {
+            assert_eq!(nodes, vec![0]);
+        }
+        unsafe {
+            libc::memset(ptr, 1, size);
+        }
+        if let Some(node) = node_of_page(ptr) {
+            assert_eq!(node, 0);
+        }
+    }
 }
@@ -5,7 +5,7 @@ use crate::utils::*;
 use core::cell::Cell;
 use lfmap::{Map, WordMap};
 use std::alloc::Global;
-use std::cmp::min;
+use std::cmp::{max, min};
 use std::marker::PhantomData;
 use std::mem;
 use std::sync::atomic::Ordering::Relaxed;
@@ -37,7 +37,7 @@ impl EvMap {
         for i in 0..nodes {
             source.push(LazyWrapper::new(Box::new(move || {
                 let node = i;
-                let node_cpus = SYS_NODE_CPUS[&node].len();
+                let node_cpus = max(usable_node_cpus(node).len(), 1);
                 let cpu_slots = min(upper_power_of_2(node_cpus), 16);
                 let mut cpu_source = Vec::with_capacity(cpu_slots);
                 debug_assert!(is_power_of_2(cpu_slots));
@@ -785,7 +785,8 @@ unsafe impl<T: Default + Copy> Send for ExchangeSlot<T> {}
 
 impl<T: Default + Copy, A: Alloc + Default> ExchangeArray<T, A> {
     pub fn new() -> Self {
-        let num_cpus = *NUM_CPU;
+        // topology reads sysfs with allocations, cannot be used by lists of the bump heap itself
+        let num_cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) } as usize;
         let default_capacity = num_cpus >> 3;
         Self::with_capacity(min(max(default_capacity, 2) as usize, MAXIMUM_EXCHANGE_SLOTS))
     }
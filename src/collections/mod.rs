@@ -3,3 +3,5 @@
 pub mod evmap;
 pub mod fixvec;
 pub mod lflist;
+pub mod pagemap;
+pub mod segmap;
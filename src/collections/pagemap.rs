@@ -0,0 +1,177 @@
+// Lock-free three level radix tree mapping pages of the 48-bit address space to words
+// Lookups are three loads without hashing. Levels are mapped from the OS on demand and never
+// returned, so readers never see freed levels. Zero means absent.
+
+use crate::mmap::{mmap_without_fd, munmap_memory};
+use crate::NULL_PTR;
+use core::mem;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering::{Acquire, Release, SeqCst};
+
+// Granularity of the map, system pages are multiples of it
+pub const PAGE_SHIFT: usize = 12;
+const LEVEL_BITS: usize = 12;
+const FANOUT: usize = 1 << LEVEL_BITS;
+const LEVEL_MASK: usize = FANOUT - 1;
+const ADDRESS_BITS: usize = PAGE_SHIFT + LEVEL_BITS * 3;
+
+type Level = [AtomicUsize; FANOUT];
+
+pub struct PageMap {
+    root: usize,
+}
+
+impl PageMap {
+    pub fn new() -> Self {
+        let root = new_level();
+        assert_ne!(root, 0, "Cannot map root of page map");
+        Self { root }
+    }
+
+    pub fn get(&self, addr: usize) -> Option<usize> {
+        if addr >> ADDRESS_BITS != 0 {
+            return None;
+        }
+        let page = addr >> PAGE_SHIFT;
+        let mid = level(self.root)[page >> (LEVEL_BITS * 2)].load(Acquire);
+        if mid == 0 {
+            return None;
+        }
+        let leaf = level(mid)[(page >> LEVEL_BITS) & LEVEL_MASK].load(Acquire);
+        if leaf == 0 {
+            return None;
+        }
+        match level(leaf)[page & LEVEL_MASK].load(Acquire) {
+            0 => None,
+            value => Some(value),
+        }
+    }
+
+    // Map all pages overlapping [addr, addr + size) to non-zero value
+    // Returns false when the system is out of memory for new levels
+    pub fn insert_range(&self, addr: usize, size: usize, value: usize) -> bool {
+        debug_assert_ne!(value, 0);
+        self.store_range(addr, size, value)
+    }
+
+    pub fn remove_range(&self, addr: usize, size: usize) {
+        self.store_range(addr, size, 0);
+    }
+
+    fn store_range(&self, addr: usize, size: usize, value: usize) -> bool {
+        if size == 0 {
+            return true;
+        }
+        if (addr + size - 1) >> ADDRESS_BITS != 0 {
+            warn!("Address {:x?} out of page map range", addr);
+            return false;
+        }
+        let first = addr >> PAGE_SHIFT;
+        let last = (addr + size - 1) >> PAGE_SHIFT;
+        for page in first..=last {
+            let leaf = match self.leaf_of(page, value != 0) {
+                Some(leaf) => leaf,
+                // nothing to remove
+                None if value == 0 => continue,
+                None => return false,
+            };
+            level(leaf)[page & LEVEL_MASK].store(value, Release);
+        }
+        true
+    }
+
+    fn leaf_of(&self, page: usize, create: bool) -> Option<usize> {
+        let mid = child_of(self.root, page >> (LEVEL_BITS * 2), create)?;
+        child_of(mid, (page >> LEVEL_BITS) & LEVEL_MASK, create)
+    }
+}
+
+fn child_of(parent: usize, index: usize, create: bool) -> Option<usize> {
+    let slot = &level(parent)[index];
+    let child = slot.load(Acquire);
+    if child != 0 {
+        return Some(child);
+    }
+    if !create {
+        return None;
+    }
+    let new_child = new_level();
+    if new_child == 0 {
+        return None;
+    }
+    let current = slot.compare_and_swap(0, new_child, SeqCst);
+    if current == 0 {
+        Some(new_child)
+    } else {
+        // other thread installed the level first
+        munmap_memory(new_child as *mut _, mem::size_of::<Level>());
+        Some(current)
+    }
+}
+
+// Fresh mapped memory is zeroed, all entries absent
+fn new_level() -> usize {
+    let ptr = mmap_without_fd(mem::size_of::<Level>());
+    if ptr == NULL_PTR {
+        0
+    } else {
+        ptr as usize
+    }
+}
+
+#[inline]
+fn level<'a>(addr: usize) -> &'a Level {
+    unsafe { &*(addr as *const Level) }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::collections::pagemap::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    pub fn general() {
+        let map = PageMap::new();
+        let page = 1 << PAGE_SHIFT;
+        assert_eq!(map.get(0x7f00_0000_0000), None);
+        assert!(map.insert_range(0x7f00_0000_0000, page * 3, 42));
+        assert_eq!(map.get(0x7f00_0000_0000), Some(42));
+        assert_eq!(map.get(0x7f00_0000_0000 + page * 3 - 1), Some(42));
+        assert_eq!(map.get(0x7f00_0000_0000 + page * 3), None);
+        // crossing leaf levels
+        let addr = (1 << (PAGE_SHIFT + 12)) - page;
+        assert!(map.insert_range(addr, page * 2, 7));
+        assert_eq!(map.get(addr), Some(7));
+        assert_eq!(map.get(addr + page), Some(7));
+        map.remove_range(0x7f00_0000_0000, page * 3);
+        assert_eq!(map.get(0x7f00_0000_0000 + page), None);
+        assert_eq!(map.get(usize::max_value()), None);
+    }
+
+    #[test]
+    pub fn parallel() {
+        let map = Arc::new(PageMap::new());
+        let page = 1 << PAGE_SHIFT;
+        let threads = (1..=16)
+            .map(|i| {
+                let map = map.clone();
+                thread::spawn(move || {
+                    for j in 0..1024 {
+                        let addr = (i << 32) + j * page * 5;
+                        assert!(map.insert_range(addr, page * 5, i * 10000 + j + 1));
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+        for i in 1..=16 {
+            for j in 0..1024 {
+                let addr = (i << 32) + j * page * 5;
+                assert_eq!(map.get(addr + page * 4), Some(i * 10000 + j + 1));
+            }
+        }
+    }
+}
@@ -0,0 +1,83 @@
+// Lock-free bitmap with one bit for each aligned segment of the 48-bit address space
+// Tags segments owned by a heap, so pointers can be told apart by one load without touching them
+// The bitmap is mapped from the OS at once, untouched parts are never committed
+
+use crate::mmap::mmap_without_fd;
+use crate::utils::is_power_of_2;
+use crate::NULL_PTR;
+use core::mem;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering::{Acquire, Release};
+
+const ADDRESS_BITS: usize = 48;
+const WORD_BITS: usize = mem::size_of::<usize>() * 8;
+
+pub struct SegmentMap {
+    words: usize,
+    shift: usize,
+}
+
+impl SegmentMap {
+    pub fn new(segment_size: usize) -> Self {
+        assert!(is_power_of_2(segment_size));
+        let shift = segment_size.trailing_zeros() as usize;
+        let num_words = (1 << (ADDRESS_BITS - shift)) / WORD_BITS;
+        let words = mmap_without_fd(num_words * mem::size_of::<usize>());
+        assert_ne!(words, NULL_PTR, "Cannot map segment map");
+        Self {
+            words: words as usize,
+            shift,
+        }
+    }
+
+    #[inline]
+    pub fn contains(&self, addr: usize) -> bool {
+        if addr >> ADDRESS_BITS != 0 {
+            return false;
+        }
+        let (word, bit) = self.position(addr);
+        word.load(Acquire) & bit != 0
+    }
+
+    // Tag the segment containing `addr`
+    pub fn insert(&self, addr: usize) {
+        debug_assert_eq!(addr >> ADDRESS_BITS, 0);
+        let (word, bit) = self.position(addr);
+        word.fetch_or(bit, Release);
+    }
+
+    pub fn remove(&self, addr: usize) {
+        debug_assert_eq!(addr >> ADDRESS_BITS, 0);
+        let (word, bit) = self.position(addr);
+        word.fetch_and(!bit, Release);
+    }
+
+    #[inline]
+    fn position(&self, addr: usize) -> (&AtomicUsize, usize) {
+        let segment = addr >> self.shift;
+        let word_addr = self.words + (segment / WORD_BITS) * mem::size_of::<usize>();
+        let word = unsafe { &*(word_addr as *const AtomicUsize) };
+        (word, 1 << (segment % WORD_BITS))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::collections::segmap::*;
+
+    #[test]
+    pub fn general() {
+        let segment_size = 512 * 1024;
+        let map = SegmentMap::new(segment_size);
+        let addr = 0x7f12_3400_0000;
+        assert!(!map.contains(addr));
+        map.insert(addr + 100);
+        assert!(map.contains(addr));
+        assert!(map.contains(addr + segment_size - 1));
+        assert!(!map.contains(addr + segment_size));
+        assert!(!map.contains(addr - 1));
+        assert!(!map.contains(usize::max_value()));
+        map.remove(addr);
+        assert!(!map.contains(addr + 100));
+    }
+}
@@ -0,0 +1,67 @@
+// C++ operator new, Itanium ABI, built by build.rs for 64-bit targets
+// Memory comes from `skyhooks_cxx_alloc` in cxx.rs, which returns null on failure and never
+// unwinds. The new-handler loop runs here, so std::bad_alloc and exceptions thrown by new-handlers
+// only unwind through C++ frames. Operator delete is in cxx.rs
+
+#include <cstddef>
+#include <new>
+
+extern "C" void *skyhooks_cxx_alloc(std::size_t size);
+extern "C" void *skyhooks_cxx_alloc_aligned(std::size_t size, std::size_t align);
+
+template <typename F>
+static void *cxx_new(F alloc) {
+    for (;;) {
+        void *ptr = alloc();
+        if (ptr != nullptr) {
+            return ptr;
+        }
+        std::new_handler handler = std::get_new_handler();
+        if (handler == nullptr) {
+            throw std::bad_alloc();
+        }
+        handler();
+    }
+}
+
+// Nothrow new runs the same loop, std::bad_alloc from new-handlers gives null
+template <typename F>
+static void *cxx_new_nothrow(F alloc) noexcept {
+    try {
+        return cxx_new(alloc);
+    } catch (const std::bad_alloc &) {
+        return nullptr;
+    }
+}
+
+void *operator new(std::size_t size) {
+    return cxx_new([=] { return skyhooks_cxx_alloc(size); });
+}
+
+void *operator new[](std::size_t size) {
+    return cxx_new([=] { return skyhooks_cxx_alloc(size); });
+}
+
+void *operator new(std::size_t size, const std::nothrow_t &) noexcept {
+    return cxx_new_nothrow([=] { return skyhooks_cxx_alloc(size); });
+}
+
+void *operator new[](std::size_t size, const std::nothrow_t &) noexcept {
+    return cxx_new_nothrow([=] { return skyhooks_cxx_alloc(size); });
+}
+
+void *operator new(std::size_t size, std::align_val_t align) {
+    return cxx_new([=] { return skyhooks_cxx_alloc_aligned(size, std::size_t(align)); });
+}
+
+void *operator new[](std::size_t size, std::align_val_t align) {
+    return cxx_new([=] { return skyhooks_cxx_alloc_aligned(size, std::size_t(align)); });
+}
+
+void *operator new(std::size_t size, std::align_val_t align, const std::nothrow_t &) noexcept {
+    return cxx_new_nothrow([=] { return skyhooks_cxx_alloc_aligned(size, std::size_t(align)); });
+}
+
+void *operator new[](std::size_t size, std::align_val_t align, const std::nothrow_t &) noexcept {
+    return cxx_new_nothrow([=] { return skyhooks_cxx_alloc_aligned(size, std::size_t(align)); });
+}
@@ -0,0 +1,155 @@
+// C++ operator new and delete, Itanium ABI mangled symbols
+// Operator new is in cxx.cc, built by build.rs. It takes memory from `skyhooks_cxx_alloc`, runs
+// the new-handler loop and throws std::bad_alloc, exceptions never unwind through Rust frames
+// Sized delete passes the size as a hint, objects larger than small heap skip its lookup
+// Symbols are mangled with `m` for 64-bit size_t, the module is only built for 64-bit targets
+
+use crate::{api, Ptr, Size};
+use core::cmp::max;
+
+// std::align_val_t is an enum of size_t
+type AlignVal = Size;
+
+// nothrow_t is an empty struct passed by reference
+type NoThrow = *const u8;
+
+extern "C" {
+    // operator new(size_t)
+    fn _Znwm(size: Size) -> Ptr;
+}
+
+// Operator new of cxx.cc is linked only when referenced from Rust
+#[used]
+static CXX_NEW: unsafe extern "C" fn(Size) -> Ptr = _Znwm;
+
+// Memory for operator new, null when out of memory, the new-handler loop is in cxx.cc
+#[no_mangle]
+pub unsafe extern "C" fn skyhooks_cxx_alloc(size: Size) -> Ptr {
+    // new of zero size should return unique pointer
+    api::nu_malloc(max(size, 1))
+}
+
+// Memory for aligned operator new, null when out of memory
+#[no_mangle]
+pub unsafe extern "C" fn skyhooks_cxx_alloc_aligned(size: Size, align: AlignVal) -> Ptr {
+    api::nu_memalign(align, max(size, 1))
+}
+
+// operator delete(void*)
+#[no_mangle]
+pub unsafe extern "C" fn _ZdlPv(ptr: Ptr) {
+    api::nu_free(ptr)
+}
+
+// operator delete[](void*)
+#[no_mangle]
+pub unsafe extern "C" fn _ZdaPv(ptr: Ptr) {
+    api::nu_free(ptr)
+}
+
+// operator delete(void*, const std::nothrow_t&)
+#[no_mangle]
+pub unsafe extern "C" fn _ZdlPvRKSt9nothrow_t(ptr: Ptr, _: NoThrow) {
+    api::nu_free(ptr)
+}
+
+// operator delete[](void*, const std::nothrow_t&)
+#[no_mangle]
+pub unsafe extern "C" fn _ZdaPvRKSt9nothrow_t(ptr: Ptr, _: NoThrow) {
+    api::nu_free(ptr)
+}
+
+// operator delete(void*, size_t)
+#[no_mangle]
+pub unsafe extern "C" fn _ZdlPvm(ptr: Ptr, size: Size) {
+    api::nu_free_sized(ptr, max(size, 1))
+}
+
+// operator delete[](void*, size_t)
+#[no_mangle]
+pub unsafe extern "C" fn _ZdaPvm(ptr: Ptr, size: Size) {
+    api::nu_free_sized(ptr, max(size, 1))
+}
+
+// operator delete(void*, std::align_val_t)
+#[no_mangle]
+pub unsafe extern "C" fn _ZdlPvSt11align_val_t(ptr: Ptr, _: AlignVal) {
+    api::nu_free(ptr)
+}
+
+// operator delete[](void*, std::align_val_t)
+#[no_mangle]
+pub unsafe extern "C" fn _ZdaPvSt11align_val_t(ptr: Ptr, _: AlignVal) {
+    api::nu_free(ptr)
+}
+
+// operator delete(void*, size_t, std::align_val_t)
+#[no_mangle]
+pub unsafe extern "C" fn _ZdlPvmSt11align_val_t(ptr: Ptr, size: Size, _: AlignVal) {
+    api::nu_free_sized(ptr, max(size, 1))
+}
+
+// operator delete[](void*, size_t, std::align_val_t)
+#[no_mangle]
+pub unsafe extern "C" fn _ZdaPvmSt11align_val_t(ptr: Ptr, size: Size, _: AlignVal) {
+    api::nu_free_sized(ptr, max(size, 1))
+}
+
+// operator delete(void*, std::align_val_t, const std::nothrow_t&)
+#[no_mangle]
+pub unsafe extern "C" fn _ZdlPvSt11align_val_tRKSt9nothrow_t(ptr: Ptr, _: AlignVal, _: NoThrow) {
+    api::nu_free(ptr)
+}
+
+// operator delete[](void*, std::align_val_t, const std::nothrow_t&)
+#[no_mangle]
+pub unsafe extern "C" fn _ZdaPvSt11align_val_tRKSt9nothrow_t(ptr: Ptr, _: AlignVal, _: NoThrow) {
+    api::nu_free(ptr)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::NULL_PTR;
+    use libc::memset;
+
+    extern "C" {
+        fn _Znam(size: Size) -> Ptr;
+        fn _ZnwmRKSt9nothrow_t(size: Size, _: NoThrow) -> Ptr;
+        fn _ZnwmSt11align_val_t(size: Size, align: AlignVal) -> Ptr;
+        fn _ZnamSt11align_val_tRKSt9nothrow_t(size: Size, align: AlignVal, _: NoThrow) -> Ptr;
+    }
+
+    #[test]
+    pub fn new_delete() {
+        unsafe {
+            for size in &[0, 1, 24, 4096, 65536, 65537, 1 << 20] {
+                let ptr = _Znwm(*size);
+                assert_ne!(ptr, NULL_PTR);
+                memset(ptr, 255, *size);
+                _ZdlPvm(ptr, *size);
+                let ptr = _Znam(*size);
+                memset(ptr, 255, *size);
+                _ZdaPv(ptr);
+            }
+            // no new-handler is installed, nothrow new gives null without throwing
+            let ptr = _ZnwmRKSt9nothrow_t(usize::max_value() - 4096, NULL_PTR as NoThrow);
+            assert_eq!(ptr, NULL_PTR);
+        }
+    }
+
+    #[test]
+    pub fn aligned_new_delete() {
+        unsafe {
+            for align in &[16, 64, 256, 4096, 1 << 16] {
+                let ptr = _ZnwmSt11align_val_t(100, *align);
+                assert_eq!(ptr as usize % *align, 0);
+                memset(ptr, 255, 100);
+                _ZdlPvmSt11align_val_t(ptr, 100, *align);
+                let ptr = _ZnamSt11align_val_tRKSt9nothrow_t(100, *align, NULL_PTR as NoThrow);
+                assert_eq!(ptr as usize % *align, 0);
+                _ZdaPvSt11align_val_t(ptr, *align);
+            }
+        }
+    }
+}
@@ -1,37 +1,91 @@
 use super::*;
-use crate::utils::is_power_of_2;
-use core::mem;
+use crate::utils::{MIN_ALIGN, NUM_NUMA_NODES, SYS_PAGE_SIZE};
+use core::cmp::{max, min};
 use libc::*;
 use std::ptr::null_mut;
 
-pub const NUM_SIZE_CLASS: usize = 16;
-
 #[derive(Clone)]
 pub struct ObjectMeta {
     pub size: usize,
     pub tid: usize,
 }
 
+// All objects are aligned to at least MIN_ALIGN, large heap objects are page aligned
 #[cfg(not(feature = "bump_heap_only"))]
 pub unsafe fn malloc(size: Size) -> Ptr {
+    malloc_aligned(size, MIN_ALIGN)
+}
+
+#[cfg(feature = "bump_heap_only")]
+pub unsafe fn malloc(size: Size) -> Ptr {
+    bump_heap::malloc(size)
+}
+
+#[cfg(not(feature = "bump_heap_only"))]
+pub unsafe fn malloc_aligned(size: Size, align: Size) -> Ptr {
+    let align = max(align, MIN_ALIGN);
     let max_small_size = *small_heap::MAXIMUM_SIZE;
-    if size > max_small_size {
+    if max(size, align) > max_small_size || align > *SYS_PAGE_SIZE {
         utils::log("LARGE MALLOC", size);
-        large_heap::allocate(size)
+        large_heap::allocate_aligned(size, align)
     } else {
         utils::log("SMALL MALLOC", size);
-        small_heap::allocate(size)
+        small_heap::allocate_aligned(size, align)
     }
 }
 
 #[cfg(feature = "bump_heap_only")]
-pub unsafe fn malloc(size: Size) -> Ptr {
-    bump_heap::malloc(size)
+pub unsafe fn malloc_aligned(size: Size, align: Size) -> Ptr {
+    bump_heap::malloc_aligned(size, align)
+}
+
+// Zeroed memory, memset is skipped for memory never used
+#[cfg(not(feature = "bump_heap_only"))]
+pub unsafe fn malloc_zeroed(size: Size, align: Size) -> Ptr {
+    let align = max(align, MIN_ALIGN);
+    let max_small_size = *small_heap::MAXIMUM_SIZE;
+    if max(size, align) > max_small_size || align > *SYS_PAGE_SIZE {
+        utils::log("LARGE MALLOC", size);
+        large_heap::allocate_zeroed(size, align)
+    } else {
+        utils::log("SMALL MALLOC", size);
+        small_heap::allocate_zeroed(size, align)
+    }
+}
+
+#[cfg(feature = "bump_heap_only")]
+pub unsafe fn malloc_zeroed(size: Size, align: Size) -> Ptr {
+    bump_heap::malloc_zeroed(size, align)
 }
 
+// Memory on NUMA `node` regardless of the node current thread runs on
+// Null when the node does not exist
+#[cfg(not(feature = "bump_heap_only"))]
+pub unsafe fn malloc_on_node(size: Size, align: Size, node: u16) -> Ptr {
+    if node >= *NUM_NUMA_NODES {
+        return NULL_PTR;
+    }
+    let align = max(align, MIN_ALIGN);
+    let max_small_size = *small_heap::MAXIMUM_SIZE;
+    if max(size, align) > max_small_size || align > *SYS_PAGE_SIZE {
+        utils::log("LARGE NODE MALLOC", size);
+        large_heap::allocate_on_node(size, align, node)
+    } else {
+        utils::log("SMALL NODE MALLOC", size);
+        small_heap::allocate_on_node(size, align, node)
+    }
+}
+
+#[cfg(feature = "bump_heap_only")]
+pub unsafe fn malloc_on_node(size: Size, align: Size, _node: u16) -> Ptr {
+    bump_heap::malloc_aligned(size, align)
+}
+
+// Small heap objects are told by their segment tag, no need to probe heaps in sequence
 #[cfg(not(feature = "bump_heap_only"))]
 pub unsafe fn free(ptr: Ptr) {
-    if small_heap::free(ptr) {
+    if small_heap::contains(ptr) {
+        small_heap::free(ptr);
         utils::log("SMALL FREE", ptr as usize);
     } else if large_heap::free(ptr) {
         utils::log("LARGE FREE", ptr as usize);
@@ -45,6 +99,25 @@ pub unsafe fn free(ptr: Ptr) {
     bump_heap::free(ptr);
 }
 
+// Objects larger than small heap sizes are in large heap, the segment tag lookup is skipped
+// Smaller sizes may still be large heap objects aligned beyond page size, see `free`
+#[cfg(not(feature = "bump_heap_only"))]
+pub unsafe fn free_sized(ptr: Ptr, size: Size) {
+    utils::log("SIZED FREE", size);
+    if size <= *small_heap::MAXIMUM_SIZE {
+        free(ptr);
+    } else if large_heap::free(ptr) {
+        utils::log("LARGE FREE", ptr as usize);
+    } else {
+        warn!("Cannot find object to free at {:x?}", ptr as usize);
+    }
+}
+
+#[cfg(feature = "bump_heap_only")]
+pub unsafe fn free_sized(ptr: Ptr, _size: Size) {
+    bump_heap::free(ptr);
+}
+
 pub unsafe fn realloc(ptr: Ptr, size: Size) -> Ptr {
     if ptr == NULL_PTR {
         return malloc(size);
@@ -53,39 +126,133 @@ pub unsafe fn realloc(ptr: Ptr, size: Size) -> Ptr {
         free(ptr);
         return NULL_PTR;
     }
-    let old_size = if let Some(size) = small_heap::size_of(ptr) {
-        size
-    } else if let Some(_) = large_heap::size_of(ptr) {
-        size
+    utils::log("REALLOC", size);
+    if let Some(new_ptr) = large_heap::realloc(ptr, size) {
+        return new_ptr;
+    }
+    // usable size of the size class, not the size requested
+    let old_size = if let Some(class_size) = small_heap::size_of(ptr) {
+        if size <= class_size && size > class_size >> 1 {
+            // kept in its size class unless shrinking by more than half
+            return ptr;
+        }
+        class_size
+    } else if let Some(usable_size) = large_heap::usable_size(ptr) {
+        if size <= usable_size && size > usable_size >> 1 {
+            // large and bump objects are kept unless shrinking by more than half
+            return ptr;
+        }
+        if size > usable_size && large_heap::try_extend(ptr, size) {
+            utils::log("EXTEND", size);
+            return ptr;
+        }
+        usable_size
     } else {
-        panic!("Cannot determinate old object");
+        warn!("Cannot determinate old object at {:x?}", ptr as usize);
         return NULL_PTR;
     };
-    if old_size >= size {
-        info!("old size is larger than requesting size, untouched");
-        return ptr;
-    }
+    // move to another size class, or out for shrinking by more than half
     let new_ptr = malloc(size);
-    memcpy(new_ptr, ptr, old_size);
+    if new_ptr == NULL_PTR {
+        // original object is untouched
+        return NULL_PTR;
+    }
+    memcpy(new_ptr, ptr, min(old_size, size));
     free(ptr);
     new_ptr
 }
 
-#[inline]
-pub fn size_class_index_from_size(size: usize) -> usize {
-    debug_assert!(size > 0);
-    if size < 2 {
-        return 0;
+pub fn size_of(ptr: Ptr) -> Option<usize> {
+    small_heap::size_of(ptr).or_else(|| large_heap::size_of(ptr))
+}
+
+#[cfg(not(feature = "bump_heap_only"))]
+pub fn usable_size(ptr: Ptr) -> Option<usize> {
+    small_heap::size_of(ptr).or_else(|| large_heap::usable_size(ptr))
+}
+
+#[cfg(feature = "bump_heap_only")]
+pub fn usable_size(ptr: Ptr) -> Option<usize> {
+    bump_heap::usable_size(ptr)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::generic_heap::{free, free_sized, malloc, malloc_aligned, realloc, usable_size};
+    use crate::Ptr;
+    use rand::{Rng, SeedableRng};
+    use rand_xorshift::XorShiftRng;
+    use std::cmp::min;
+
+    unsafe fn fill(ptr: Ptr, size: usize, seed: u8) {
+        for i in 0..size {
+            *((ptr as usize + i) as *mut u8) = seed.wrapping_add(i as u8);
+        }
     }
-    let log = log_2_of(size);
-    if is_power_of_2(size) && log > 0 {
-        log - 1
-    } else {
-        log
+
+    unsafe fn check(ptr: Ptr, size: usize, seed: u8) {
+        for i in 0..size {
+            assert_eq!(*((ptr as usize + i) as *mut u8), seed.wrapping_add(i as u8));
+        }
+    }
+
+    #[test]
+    pub fn realloc_contents() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        for round in 0..16 {
+            unsafe {
+                let mut size = rng.gen_range(1, 1024);
+                let mut ptr = malloc(size);
+                let mut seed = round as u8;
+                fill(ptr, size, seed);
+                for _ in 0..64 {
+                    let new_size = match rng.gen_range(0, 4) {
+                        0 => rng.gen_range(1, 128),
+                        1 => rng.gen_range(1, 8192),
+                        2 => rng.gen_range(1, 256 * 1024),
+                        _ => rng.gen_range(1, 4 * 1024 * 1024),
+                    };
+                    ptr = realloc(ptr, new_size);
+                    assert!(usable_size(ptr).unwrap() >= new_size);
+                    check(ptr, min(size, new_size), seed);
+                    seed = seed.wrapping_add(1);
+                    fill(ptr, new_size, seed);
+                    size = new_size;
+                }
+                free(ptr);
+            }
+        }
+    }
+
+    #[test]
+    pub fn realloc_size_class() {
+        unsafe {
+            let ptr = malloc(1000);
+            // same size class
+            assert_eq!(realloc(ptr, 1024), ptr);
+            assert_eq!(realloc(ptr, 900), ptr);
+            // shrinking within half of the size class stays in place
+            assert_eq!(realloc(ptr, 600), ptr);
+            // shrinking by more than half moves to a smaller size class
+            let small = realloc(ptr, 100);
+            assert_ne!(small, ptr);
+            assert!(usable_size(small).unwrap() < 1024);
+            free(small);
+        }
     }
-}
 
-#[inline]
-pub fn log_2_of(num: usize) -> usize {
-    mem::size_of::<usize>() * 8 - num.leading_zeros() as usize - 1
+    #[test]
+    pub fn free_sized_hint() {
+        unsafe {
+            for size in &[100, 4096, 1 << 20] {
+                let ptr = malloc(*size);
+                assert!(usable_size(ptr).unwrap() >= *size);
+                free_sized(ptr, *size);
+            }
+            // small size aligned beyond page size is in large heap
+            let ptr = malloc_aligned(100, 1 << 16);
+            assert_eq!(ptr as usize % (1 << 16), 0);
+            free_sized(ptr, 100);
+        }
+    }
 }
@@ -1,28 +1,394 @@
 // Heap for large objects exceeds maximum tier of pages
-// Use bump heap
+// Use bump heap, objects cannot fit in bump heap address space are mapped directly
+// All objects are page aligned and recorded at their first page in a radix page map, pages of
+// large objects are never shared so lookups are three loads without hashing
 
+use crate::bump_heap::BumpAllocator;
+use crate::collections::pagemap::PageMap;
+use crate::mmap::{bind_to_node, munmap_memory, numa_policy, remap_memory, NumaPolicy};
 use crate::mmap_heap::MmapAllocator;
+use crate::small_heap;
 use crate::utils::align_padding;
-use crate::utils::SYS_PAGE_SIZE;
-use crate::Ptr;
-use core::alloc::{Alloc, Layout};
+use crate::utils::{CACHE_LINE_SIZE, SYS_PAGE_SIZE};
+use crate::{Ptr, NULL_PTR};
+use core::alloc::{Alloc, GlobalAlloc, Layout};
+use core::cmp::max;
+use core::ptr::NonNull;
+
+// Heaps large objects come from, in low bits of page map entries
+const KIND_MAPPED: usize = 1;
+const KIND_BUMP: usize = 2;
+const KIND_NODE: usize = 3;
+const KIND_MASK: usize = 3;
+// Bits above kind hold log2 of alignment of objects from bump heaps, sizes are page multiples
+const ALIGN_SHIFT: usize = 2;
+const ALIGN_MASK: usize = 0x3f;
+
+lazy_static! {
+    // First page of each object to its page aligned size, alignment and heap
+    static ref LARGE_PAGES: PageMap = PageMap::new();
+}
+
+#[derive(Clone, Copy)]
+struct LargeObject {
+    kind: usize,
+    size: usize,
+    align: usize,
+}
+
+impl LargeObject {
+    #[inline]
+    fn layout(&self) -> Layout {
+        Layout::from_size_align(self.size, self.align).unwrap()
+    }
+}
+
+// Returns false when the system is out of memory for page map levels
+fn record(ptr: Ptr, kind: usize, size: usize, align: usize) -> bool {
+    debug_assert_eq!(size % *SYS_PAGE_SIZE, 0);
+    let entry = size | (align.trailing_zeros() as usize) << ALIGN_SHIFT | kind;
+    LARGE_PAGES.insert_range(ptr as usize, 1, entry)
+}
+
+fn forget(ptr: Ptr) {
+    LARGE_PAGES.remove_range(ptr as usize, 1);
+}
+
+// Only object addresses are recorded, other addresses in the first page are no object
+#[inline]
+fn lookup(ptr: Ptr) -> Option<LargeObject> {
+    let addr = ptr as usize;
+    if addr % *SYS_PAGE_SIZE != 0 {
+        return None;
+    }
+    LARGE_PAGES.get(addr).map(|entry| LargeObject {
+        kind: entry & KIND_MASK,
+        size: entry & !(*SYS_PAGE_SIZE - 1),
+        align: 1 << ((entry >> ALIGN_SHIFT) & ALIGN_MASK),
+    })
+}
 
 pub unsafe fn allocate(size: usize) -> Ptr {
+    allocate_aligned(size, CACHE_LINE_SIZE)
+}
+pub unsafe fn allocate_aligned(size: usize, align: usize) -> Ptr {
+    allocate_object(size, align, false)
+}
+pub unsafe fn allocate_zeroed(size: usize, align: usize) -> Ptr {
+    allocate_object(size, align, true)
+}
+
+unsafe fn allocate_object(size: usize, align: usize, zeroed: bool) -> Ptr {
     let page_size = *SYS_PAGE_SIZE;
     let padding = align_padding(size, page_size);
-    let total_size = size + padding;
+    let total_size = match size.checked_add(padding) {
+        Some(s) => s,
+        None => return NULL_PTR,
+    };
     if total_size < crate::bump_heap::HEAP_VIRT_SIZE {
-        crate::bump_heap::malloc(total_size)
+        let align = max(align, page_size);
+        let layout = match Layout::from_size_align(total_size, align) {
+            Ok(layout) => layout,
+            Err(_) => return NULL_PTR,
+        };
+        let ptr = if zeroed {
+            BumpAllocator.alloc_zeroed(layout) as Ptr
+        } else {
+            BumpAllocator.alloc(layout) as Ptr
+        };
+        if ptr != NULL_PTR && !record(ptr, KIND_BUMP, total_size, align) {
+            BumpAllocator.dealloc(ptr as *mut u8, layout);
+            return NULL_PTR;
+        }
+        ptr
     } else {
-        let mut ma = MmapAllocator;
-        ma.alloc(Layout::from_size_align(size, 1).unwrap())
-            .unwrap()
-            .as_ptr() as Ptr
+        // fresh mapped memory is always zeroed
+        let ptr = map_aligned(total_size, align);
+        if ptr != NULL_PTR && !record(ptr, KIND_MAPPED, total_size, page_size) {
+            munmap_memory(ptr, total_size);
+            return NULL_PTR;
+        }
+        ptr
+    }
+}
+
+// Objects placed on `node` come from the bump heap of the node, its address space is bound already
+// Objects cannot fit in bump heap address space are mapped directly and bound before pages are
+// touched, remapping keeps the policy when they are resized
+pub unsafe fn allocate_on_node(size: usize, align: usize, node: u16) -> Ptr {
+    let page_size = *SYS_PAGE_SIZE;
+    let padding = align_padding(size, page_size);
+    let total_size = match size.checked_add(padding) {
+        Some(s) => s,
+        None => return NULL_PTR,
+    };
+    if total_size < crate::bump_heap::HEAP_VIRT_SIZE {
+        let align = max(align, page_size);
+        let layout = match Layout::from_size_align(total_size, align) {
+            Ok(layout) => layout,
+            Err(_) => return NULL_PTR,
+        };
+        let allocator = small_heap::node_bump_allocator(node);
+        let ptr = allocator.alloc(layout) as Ptr;
+        if ptr != NULL_PTR && !record(ptr, KIND_NODE, total_size, align) {
+            allocator.dealloc(ptr as *mut u8, layout);
+            return NULL_PTR;
+        }
+        return ptr;
+    }
+    let ptr = map_aligned(total_size, align);
+    if ptr == NULL_PTR {
+        return NULL_PTR;
+    }
+    // the node is asked for explicitly, pages are not left to first touch or spread
+    let policy = match numa_policy() {
+        NumaPolicy::Bind => NumaPolicy::Bind,
+        _ => NumaPolicy::Preferred,
+    };
+    bind_to_node(ptr, total_size, node, policy);
+    if !record(ptr, KIND_MAPPED, total_size, page_size) {
+        munmap_memory(ptr, total_size);
+        return NULL_PTR;
+    }
+    ptr
+}
+// Mapped memory is page aligned, for larger alignment, map more and trim both ends
+// Sizes no layout can describe, beyond isize::MAX, are out of memory
+unsafe fn map_aligned(size: usize, align: usize) -> Ptr {
+    let page_size = *SYS_PAGE_SIZE;
+    let mut ma = MmapAllocator;
+    if align <= page_size {
+        return match Layout::from_size_align(size, page_size).map(|l| ma.alloc(l)) {
+            Ok(Ok(ptr)) => ptr.as_ptr() as Ptr,
+            _ => NULL_PTR,
+        };
+    }
+    let map_size = match size.checked_add(align) {
+        Some(s) => s,
+        None => return NULL_PTR,
+    };
+    let base = match Layout::from_size_align(map_size, page_size).map(|l| ma.alloc(l)) {
+        Ok(Ok(ptr)) => ptr.as_ptr() as usize,
+        _ => return NULL_PTR,
+    };
+    let head = align_padding(base, align);
+    let addr = base + head;
+    let tail = map_size - head - size;
+    if head > 0 {
+        munmap_memory(base as Ptr, head);
     }
+    if tail > 0 {
+        munmap_memory((addr + size) as Ptr, tail);
+    }
+    addr as Ptr
 }
 pub unsafe fn free(ptr: Ptr) -> bool {
-    crate::bump_heap::free(ptr)
+    let object = match lookup(ptr) {
+        Some(object) => object,
+        // objects allocated by re-entrant calls are in the bump heap without a record here
+        None => return crate::bump_heap::free(ptr),
+    };
+    forget(ptr);
+    match object.kind {
+        KIND_MAPPED => {
+            let mut ma = MmapAllocator;
+            ma.dealloc(NonNull::new(ptr as *mut u8).unwrap(), object.layout());
+        }
+        KIND_BUMP => BumpAllocator.dealloc(ptr as *mut u8, object.layout()),
+        // objects from bump heaps of nodes go back to the heap they are from
+        _ => {
+            if let Some(allocator) = small_heap::node_bump_allocator_of(ptr) {
+                allocator.dealloc(ptr as *mut u8, object.layout());
+            }
+        }
+    }
+    true
+}
+// Resize directly mapped objects by remapping their pages, content is not copied
+// Returns None if the object is not directly mapped, it may share address space in bump heap
+pub unsafe fn realloc(ptr: Ptr, size: usize) -> Option<Ptr> {
+    let old_size = lookup(ptr).filter(|o| o.kind == KIND_MAPPED)?.size;
+    let page_size = *SYS_PAGE_SIZE;
+    let new_size = match size.checked_add(align_padding(size, page_size)) {
+        Some(s) => s,
+        None => return Some(NULL_PTR),
+    };
+    if new_size == old_size {
+        return Some(ptr);
+    }
+    let new_ptr = remap_memory(ptr, old_size, new_size);
+    if new_ptr != NULL_PTR {
+        forget(ptr);
+        if !record(new_ptr, KIND_MAPPED, new_size, page_size) {
+            warn!("Cannot record remapped object at {:x?}", new_ptr as usize);
+        }
+    }
+    Some(new_ptr)
+}
+// Grow object in bump heap without moving, only possible when it is at the tail
+pub unsafe fn try_extend(ptr: Ptr, size: usize) -> bool {
+    let object = match lookup(ptr) {
+        Some(object) if object.kind == KIND_BUMP => object,
+        _ => return false,
+    };
+    let page_size = *SYS_PAGE_SIZE;
+    let total_size = match size.checked_add(align_padding(size, page_size)) {
+        Some(total_size) if total_size < crate::bump_heap::HEAP_VIRT_SIZE => total_size,
+        _ => return false,
+    };
+    let new_layout = Layout::from_size_align(total_size, object.align).unwrap();
+    let allocator = crate::bump_heap::general_allocator();
+    // the first page is kept, updating the record in place is atomic
+    allocator.try_extend(ptr as *mut u8, object.layout(), new_layout)
+        && record(ptr, KIND_BUMP, total_size, object.align)
 }
 pub fn size_of(ptr: Ptr) -> Option<usize> {
-    crate::bump_heap::size_of(ptr)
+    lookup(ptr)
+        .map(|object| object.size)
+        .or_else(|| crate::bump_heap::size_of(ptr))
+}
+pub fn usable_size(ptr: Ptr) -> Option<usize> {
+    let object = match lookup(ptr) {
+        Some(object) => object,
+        None => return crate::bump_heap::usable_size(ptr),
+    };
+    let addr = ptr as *mut u8;
+    match object.kind {
+        KIND_MAPPED => Some(object.size),
+        KIND_BUMP => crate::bump_heap::general_allocator().usable_size(addr, object.layout()),
+        _ => small_heap::node_bump_allocator_of(ptr)?.usable_size(addr, object.layout()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::large_heap::{
+        allocate, allocate_aligned, allocate_on_node, free, lookup, realloc, size_of, usable_size,
+        KIND_BUMP, KIND_MAPPED,
+    };
+    use crate::Ptr;
+    use std::fs;
+
+    const SIZE: usize = 300 * 1024 * 1024;
+
+    fn num_mappings() -> usize {
+        fs::read_to_string("/proc/self/maps")
+            .unwrap()
+            .lines()
+            .count()
+    }
+
+    fn rss_pages() -> usize {
+        let statm = fs::read_to_string("/proc/self/statm").unwrap();
+        statm.split_whitespace().nth(1).unwrap().parse().unwrap()
+    }
+
+    unsafe fn touch(ptr: Ptr, size: usize) {
+        for offset in (0..size).step_by(4096) {
+            *((ptr as usize + offset) as *mut u8) = 1;
+        }
+    }
+
+    #[test]
+    pub fn beyond_isize() {
+        unsafe {
+            assert!(allocate(usize::max_value() - 4096).is_null());
+            assert!(allocate_aligned(isize::max_value() as usize, 1 << 20).is_null());
+        }
+    }
+
+    #[test]
+    pub fn size() {
+        unsafe {
+            let ptr = allocate(SIZE + 1);
+            assert!(size_of(ptr).unwrap() > SIZE);
+            assert!(usable_size(ptr).unwrap() > SIZE);
+            assert!(free(ptr));
+            assert_eq!(size_of(ptr), None);
+        }
+    }
+
+    #[test]
+    pub fn repeated_alloc_free() {
+        unsafe {
+            let ptr = allocate(SIZE);
+            touch(ptr, SIZE);
+            assert!(free(ptr));
+            let mappings = num_mappings();
+            let rss = rss_pages();
+            for _ in 0..20 {
+                let ptr = allocate(SIZE);
+                touch(ptr, SIZE);
+                assert!(free(ptr));
+            }
+            // allow unrelated mappings and pages from test harness, far less than a single object
+            assert!(num_mappings() <= mappings + 16);
+            assert!(rss_pages() < rss + SIZE / 4096 / 2);
+        }
+    }
+
+    #[test]
+    pub fn remap() {
+        unsafe {
+            let ptr = allocate(SIZE);
+            let last = SIZE - 1;
+            *(ptr as *mut u8) = 42;
+            *((ptr as usize + last) as *mut u8) = 24;
+            let ptr = realloc(ptr, SIZE * 2).unwrap();
+            assert_eq!(size_of(ptr), Some(SIZE * 2));
+            assert_eq!(*(ptr as *mut u8), 42);
+            assert_eq!(*((ptr as usize + last) as *mut u8), 24);
+            touch(ptr, SIZE * 2);
+            let ptr = realloc(ptr, SIZE / 2).unwrap();
+            assert_eq!(size_of(ptr), Some(SIZE / 2));
+            assert_eq!(*(ptr as *mut u8), 1);
+            assert!(free(ptr));
+            // objects in bump heap cannot be remapped
+            let ptr = allocate(1024 * 1024);
+            assert_eq!(realloc(ptr, SIZE), None);
+            assert!(free(ptr));
+        }
+    }
+
+    #[test]
+    pub fn page_records() {
+        unsafe {
+            let size = 1024 * 1024;
+            let ptr = allocate(size + 1);
+            assert_eq!(ptr as usize % 4096, 0);
+            assert_eq!(lookup(ptr).unwrap().kind, KIND_BUMP);
+            assert_eq!(size_of(ptr), Some(size + 4096));
+            // only the object address is recorded
+            assert_eq!(lookup((ptr as usize + 64) as Ptr).map(|o| o.kind), None);
+            assert_eq!(lookup((ptr as usize + 4096) as Ptr).map(|o| o.kind), None);
+            assert!(free(ptr));
+            assert_eq!(lookup(ptr).map(|o| o.kind), None);
+            let ptr = allocate(SIZE);
+            assert_eq!(lookup(ptr).unwrap().kind, KIND_MAPPED);
+            assert!(free(ptr));
+            assert_eq!(size_of(ptr), None);
+            // objects of re-entrant calls have no record, they are still found in the bump heap
+            let ptr = crate::bump_heap::malloc(64);
+            assert!(size_of(ptr).is_some());
+            assert!(free(ptr));
+        }
+    }
+
+    #[test]
+    pub fn node_heap() {
+        unsafe {
+            let size = 1024 * 1024;
+            let ptr = allocate_on_node(size, 4096, 0);
+            touch(ptr, size);
+            assert_eq!(size_of(ptr), Some(size));
+            assert!(usable_size(ptr).unwrap() >= size);
+            // served from the node bump heap, cannot be remapped
+            assert_eq!(realloc(ptr, SIZE), None);
+            assert!(free(ptr));
+            assert_eq!(size_of(ptr), None);
+            let ptr = allocate_on_node(size, 1 << 20, 0);
+            assert_eq!(ptr as usize % (1 << 20), 0);
+            assert!(free(ptr));
+        }
+    }
 }
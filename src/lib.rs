@@ -1,4 +1,6 @@
 // Gave up on no_std for filesystem is required for this allocator to get CPU related information
+// Preload the cdylib by LD_PRELOAD to replace malloc family and C++ operator new and delete
+// Failed operator new throws std::bad_alloc from a C++ shim, see `cxx`
 
 #![feature(alloc_layout_extra)]
 #![feature(alloc_error_handler)]
@@ -17,12 +19,18 @@ extern crate test;
 
 pub mod api;
 mod bump_heap;
+#[cfg(target_pointer_width = "64")]
+mod cxx;
 mod generic_heap;
 mod large_heap;
 mod mmap;
 mod mmap_heap;
 mod rand;
+mod rseq;
+pub mod scavenger;
+mod size_class;
 mod small_heap;
+pub mod topology;
 mod utils;
 
 mod collections;
@@ -33,30 +41,182 @@ pub type Void = libc::c_void;
 pub const NULL: usize = 0;
 pub const NULL_PTR: *mut c_void = NULL as *mut c_void;
 
+pub use crate::mmap::NumaPolicy;
+
 use crate::api::SkyhooksAllocator;
 use crate::bump_heap::BumpAllocator;
+use crate::utils::{is_power_of_2, SYS_PAGE_SIZE};
+use core::alloc::Layout;
+use core::cmp::max;
 use core::ffi::c_void;
+use core::mem;
+use errno::{set_errno, Errno};
+use libc::{c_int, EINVAL, ENOMEM};
+
+#[no_mangle]
+pub unsafe extern "C" fn malloc(size: Size) -> Ptr {
+    api::nu_malloc(size)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn free(ptr: Ptr) {
+    api::nu_free(ptr)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn calloc(nmemb: Size, size: Size) -> Ptr {
+    api::nu_calloc(nmemb, size)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn realloc(ptr: Ptr, size: Size) -> Ptr {
+    api::nu_realloc(ptr, size)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn reallocarray(ptr: Ptr, nmemb: Size, size: Size) -> Ptr {
+    api::nu_reallocarray(ptr, nmemb, size)
+}
 
+// glibc internal entry points, used by some libraries to bypass interposed malloc
 #[no_mangle]
-pub unsafe fn malloc(size: Size) -> Ptr {
+pub unsafe extern "C" fn __libc_malloc(size: Size) -> Ptr {
     api::nu_malloc(size)
 }
 
 #[no_mangle]
-pub unsafe fn free(ptr: Ptr) {
+pub unsafe extern "C" fn __libc_free(ptr: Ptr) {
     api::nu_free(ptr)
 }
 
 #[no_mangle]
-pub unsafe fn calloc(nmemb: Size, size: Size) -> Ptr {
+pub unsafe extern "C" fn __libc_calloc(nmemb: Size, size: Size) -> Ptr {
     api::nu_calloc(nmemb, size)
 }
 
 #[no_mangle]
-pub unsafe fn realloc(ptr: Ptr, size: Size) -> Ptr {
+pub unsafe extern "C" fn __libc_realloc(ptr: Ptr, size: Size) -> Ptr {
     api::nu_realloc(ptr, size)
 }
 
+#[no_mangle]
+pub unsafe extern "C" fn __libc_memalign(alignment: Size, size: Size) -> Ptr {
+    memalign(alignment, size)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn malloc_usable_size(ptr: Ptr) -> Size {
+    api::nu_usable_size(ptr)
+}
+
+// Release free memory to the OS, `pad` is ignored for there is no single heap top to keep
+// Returns 1 if any memory was released, 0 otherwise
+#[no_mangle]
+pub unsafe extern "C" fn malloc_trim(_pad: Size) -> c_int {
+    (purge() > 0) as c_int
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn posix_memalign(memptr: *mut Ptr, alignment: Size, size: Size) -> c_int {
+    if alignment == 0 || !is_power_of_2(alignment) || alignment % mem::size_of::<Ptr>() != 0 {
+        return EINVAL;
+    }
+    let ptr = api::nu_memalign(alignment, size);
+    if ptr == NULL_PTR && size != 0 {
+        return ENOMEM;
+    }
+    *memptr = ptr;
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn aligned_alloc(alignment: Size, size: Size) -> Ptr {
+    memalign(alignment, size)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn memalign(alignment: Size, size: Size) -> Ptr {
+    if alignment == 0 || !is_power_of_2(alignment) {
+        set_errno(Errno(EINVAL));
+        return NULL_PTR;
+    }
+    aligned_or_enomem(alignment, size)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn valloc(size: Size) -> Ptr {
+    aligned_or_enomem(*SYS_PAGE_SIZE, size)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn pvalloc(size: Size) -> Ptr {
+    let page_size = *SYS_PAGE_SIZE;
+    // round up to page size, at least one page
+    let size = match size.checked_add(page_size - 1) {
+        Some(s) => max(s & !(page_size - 1), page_size),
+        None => {
+            set_errno(Errno(ENOMEM));
+            return NULL_PTR;
+        }
+    };
+    aligned_or_enomem(page_size, size)
+}
+
+#[inline]
+unsafe fn aligned_or_enomem(alignment: Size, size: Size) -> Ptr {
+    let ptr = api::nu_memalign(alignment, size);
+    if ptr == NULL_PTR && size != 0 {
+        set_errno(Errno(ENOMEM));
+    }
+    ptr
+}
+
+// Allocate `size` bytes on NUMA `node`, for threads preparing memory for other nodes
+// Returns null if the node does not exist or out of memory. Free as usual
+#[no_mangle]
+pub unsafe extern "C" fn sk_malloc_onnode(size: Size, node: c_int) -> Ptr {
+    if node < 0 || node > u16::max_value() as c_int {
+        set_errno(Errno(EINVAL));
+        return NULL_PTR;
+    }
+    api::nu_malloc_on_node(1, size, node as u16)
+}
+
+// Actual size of the memory block at `ptr` can be used without realloc
+// Returns None if the block is not allocated by skyhooks
+pub fn usable_size(ptr: *const u8) -> Option<Size> {
+    match api::nu_usable_size(ptr as Ptr) {
+        0 => None,
+        size => Some(size),
+    }
+}
+
+// Return all free pages to the OS regardless of scavenger decay
+// Bump heap pages not wholly covered by a free object are kept, see `scavenger`
+// Returns the number of bytes released
+pub fn purge() -> usize {
+    scavenger::purge()
+}
+
+// Allocate memory for `layout` on NUMA `node`, release it by `free` or `SkyhooksAllocator`
+// Returns null if the node does not exist or out of memory
+pub unsafe fn alloc_on_node(layout: Layout, node: u16) -> *mut u8 {
+    api::nu_malloc_on_node(layout.align(), layout.size(), node) as *mut u8
+}
+
+// Tune per-thread cache, magazines refill and flush `objects` at once but no more than `bytes`
+// Zero objects disables thread cache, batches over 64 objects are clamped to 64
+pub fn set_thread_cache_batch(objects: usize, bytes: usize) {
+    small_heap::set_cache_batch(objects, bytes)
+}
+
+// Policy placing node-local heaps on their NUMA node, for address space reserved afterwards
+// Defaults to SKYHOOKS_NUMA_POLICY of bind, preferred, interleave or none, or preferred
+// Interleave spreads node-local heaps over all nodes and gives up their locality
+pub fn set_numa_policy(policy: NumaPolicy) {
+    mmap::set_numa_policy(policy)
+}
+
 //#[global_allocator]
 //#[cfg(not(feature = "bump_heap_only"))]
 //static INNER_ALLOCATOR: SkyhooksAllocator = SkyhooksAllocator;
@@ -1,10 +1,52 @@
 use super::*;
+use crate::utils::NUM_NUMA_NODES;
+use core::cmp::min;
+use core::mem;
 use core::ptr;
+use core::sync::atomic::Ordering::Relaxed;
+use core::sync::atomic::{AtomicBool, AtomicU8};
 use errno::errno;
 use libc::*;
+use std::env;
 
 const MADV_NOHUGEPAGE: c_int = 14;
 
+// Memory policy modes and flags from linux/mempolicy.h
+const MPOL_PREFERRED: c_int = 1;
+const MPOL_BIND: c_int = 2;
+const MPOL_INTERLEAVE: c_int = 3;
+#[cfg(all(test, target_os = "linux"))]
+const MPOL_F_ADDR: c_ulong = 2;
+// Nodes covered by node masks
+const MAX_NODES: usize = 1024;
+const NODE_MASK_WORDS: usize = MAX_NODES / (mem::size_of::<c_ulong>() * 8);
+
+const NUMA_POLICY_ENV: &str = "SKYHOOKS_NUMA_POLICY";
+
+type NodeMask = [c_ulong; NODE_MASK_WORDS];
+
+// NUMA policy of address space reserved for node-local heaps
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NumaPolicy {
+    // Pages only from the node, allocations fail when the node is out of memory
+    Bind = 0,
+    // Pages from the node while it has free memory, then from others
+    Preferred = 1,
+    // Pages spread over all nodes round-robin, node-local heaps are no longer local to their node
+    // and threads get remote memory for most allocations. For bandwidth bound workloads only
+    Interleave = 2,
+    // No policy, pages are placed on the node first touching them
+    FirstTouch = 3,
+}
+
+// Failed mbind is reported once, it fails again for every heap on a node the kernel does not have
+static MBIND_WARNED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref NUMA_POLICY: AtomicU8 = AtomicU8::new(numa_policy_from_env() as u8);
+}
+
+// Returns null pointer on failure, caller should report out of memory
 pub fn mmap_without_fd(size: usize) -> Ptr {
     let ptr = unsafe {
         mmap(
@@ -18,7 +60,8 @@ pub fn mmap_without_fd(size: usize) -> Ptr {
     };
     if ptr == -1 as isize as *mut c_void {
         let err = errno();
-        panic!("mmap failed: [{}] {}", err.0, err);
+        warn!("mmap failed: [{}] {}", err.0, err);
+        return NULL_PTR;
     };
     no_huge_page(ptr, size);
     ptr
@@ -30,6 +73,31 @@ pub fn munmap_memory(address: Ptr, size: usize) {
     }
 }
 
+// Resize mapping, pages may move to a new address without copying
+// Returns null pointer on failure and the original mapping is untouched
+#[cfg(target_os = "linux")]
+pub fn remap_memory(address: Ptr, old_size: usize, new_size: usize) -> Ptr {
+    let ptr = unsafe { mremap(address, old_size, new_size, MREMAP_MAYMOVE) };
+    if ptr == MAP_FAILED {
+        let err = errno();
+        warn!("mremap failed: [{}] {}", err.0, err);
+        return NULL_PTR;
+    }
+    ptr
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn remap_memory(address: Ptr, old_size: usize, new_size: usize) -> Ptr {
+    let ptr = mmap_without_fd(new_size);
+    if ptr != NULL_PTR {
+        unsafe {
+            memcpy(ptr, address, min(old_size, new_size));
+        }
+        munmap_memory(address, old_size);
+    }
+    ptr
+}
+
 #[cfg(target_os = "linux")]
 #[inline]
 pub fn no_huge_page(ptr: Ptr, size: usize) {
@@ -54,9 +122,141 @@ pub fn dealloc_regional(addr: Ptr, size: usize) -> usize {
     unsafe { madvise(addr, size, MADV_DONTNEED) as usize }
 }
 
+// Drop pages immediately, following access gets zeroed pages
+#[inline]
+pub fn release_regional(addr: Ptr, size: usize) -> usize {
+    unsafe { madvise(addr, size, MADV_DONTNEED) as usize }
+}
+
+impl NumaPolicy {
+    // Parse policy names `bind`, `preferred`, `interleave` and `none`
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "bind" => Some(NumaPolicy::Bind),
+            "preferred" => Some(NumaPolicy::Preferred),
+            "interleave" => Some(NumaPolicy::Interleave),
+            "none" => Some(NumaPolicy::FirstTouch),
+            _ => None,
+        }
+    }
+
+    fn from_u8(policy: u8) -> Self {
+        match policy {
+            0 => NumaPolicy::Bind,
+            1 => NumaPolicy::Preferred,
+            2 => NumaPolicy::Interleave,
+            _ => NumaPolicy::FirstTouch,
+        }
+    }
+}
+
+fn numa_policy_from_env() -> NumaPolicy {
+    match env::var(NUMA_POLICY_ENV) {
+        Ok(name) => NumaPolicy::parse(&name).unwrap_or_else(|| {
+            warn!("Invalid {} {}, using preferred", NUMA_POLICY_ENV, name);
+            NumaPolicy::Preferred
+        }),
+        Err(_) => NumaPolicy::Preferred,
+    }
+}
+
+pub fn numa_policy() -> NumaPolicy {
+    NumaPolicy::from_u8(NUMA_POLICY.load(Relaxed))
+}
+
+// Takes effect on address space reserved afterwards
+pub fn set_numa_policy(policy: NumaPolicy) {
+    NUMA_POLICY.store(policy as u8, Relaxed);
+}
+
+// Apply the policy for `node` to untouched pages of the range
+// Returns false when the kernel refuses, e.g. no such node or no NUMA support,
+// pages are placed by first touch then
+#[cfg(target_os = "linux")]
+pub fn bind_to_node(addr: Ptr, size: usize, node: u16, policy: NumaPolicy) -> bool {
+    let mut mask: NodeMask = [0; NODE_MASK_WORDS];
+    let mode = match policy {
+        NumaPolicy::Bind => MPOL_BIND,
+        NumaPolicy::Preferred => MPOL_PREFERRED,
+        NumaPolicy::Interleave => MPOL_INTERLEAVE,
+        NumaPolicy::FirstTouch => return true,
+    };
+    if policy == NumaPolicy::Interleave {
+        (0..*NUM_NUMA_NODES).for_each(|n| set_node(&mut mask, n));
+    } else {
+        set_node(&mut mask, node);
+    }
+    let res = unsafe {
+        // kernel takes one more than the number of bits in the mask
+        let max_node = MAX_NODES as c_ulong + 1;
+        syscall(SYS_mbind, addr, size, mode, mask.as_ptr(), max_node, 0)
+    };
+    if res != 0 {
+        let err = errno();
+        if !MBIND_WARNED.swap(true, Relaxed) {
+            warn!("mbind to node {} failed: [{}] {}", node, err.0, err);
+        } else {
+            debug!("mbind to node {} failed: [{}] {}", node, err.0, err);
+        }
+        return false;
+    }
+    true
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn bind_to_node(_addr: Ptr, _size: usize, _node: u16, _policy: NumaPolicy) -> bool {
+    false
+}
+
+// Policy mode of the mapping containing `addr` and its node mask, for tests to check placement
+#[cfg(all(test, target_os = "linux"))]
+pub fn memory_policy_of(addr: Ptr) -> Option<(c_int, Vec<u16>)> {
+    let mut mode: c_int = 0;
+    let mut mask: NodeMask = [0; NODE_MASK_WORDS];
+    let res = unsafe {
+        syscall(
+            SYS_get_mempolicy,
+            &mut mode,
+            mask.as_mut_ptr(),
+            MAX_NODES as c_ulong,
+            addr,
+            MPOL_F_ADDR,
+        )
+    };
+    if res != 0 {
+        return None;
+    }
+    let bits = mem::size_of::<c_ulong>() * 8;
+    let nodes = (0..MAX_NODES)
+        .filter(|n| mask[n / bits] & (1 << (n % bits)) != 0)
+        .map(|n| n as u16)
+        .collect();
+    Some((mode, nodes))
+}
+
+// Node of the page at `addr`, None if the page is not present, for tests to check placement
+#[cfg(all(test, target_os = "linux"))]
+pub fn node_of_page(addr: Ptr) -> Option<u16> {
+    let mut status: c_int = -1;
+    let pages = [addr];
+    // query only, without target nodes
+    let nodes: *const c_int = ptr::null();
+    let res = unsafe { syscall(SYS_move_pages, 0, 1, pages.as_ptr(), nodes, &mut status, 0) };
+    if res != 0 || status < 0 {
+        return None;
+    }
+    Some(status as u16)
+}
+
+fn set_node(mask: &mut NodeMask, node: u16) {
+    let bits = mem::size_of::<c_ulong>() * 8;
+    mask[node as usize / bits] |= 1 << (node as usize % bits);
+}
+
 #[cfg(test)]
 mod test {
-    use crate::mmap::mmap_without_fd;
+    use crate::mmap::*;
+    use crate::utils::SYS_PAGE_SIZE;
     use core::mem;
 
     #[test]
@@ -67,4 +267,40 @@ mod test {
         }
         assert_eq!(val, 99);
     }
+
+    #[test]
+    pub fn policy_names() {
+        assert_eq!(NumaPolicy::parse("bind"), Some(NumaPolicy::Bind));
+        assert_eq!(NumaPolicy::parse("Preferred"), Some(NumaPolicy::Preferred));
+        let interleave = NumaPolicy::parse(" interleave");
+        assert_eq!(interleave, Some(NumaPolicy::Interleave));
+        assert_eq!(NumaPolicy::parse("none"), Some(NumaPolicy::FirstTouch));
+        assert_eq!(NumaPolicy::parse("local"), None);
+    }
+
+    #[test]
+    pub fn bind_node_zero() {
+        let size = *SYS_PAGE_SIZE * 4;
+        let policies = [
+            (NumaPolicy::Bind, MPOL_BIND),
+            (NumaPolicy::Preferred, MPOL_PREFERRED),
+            (NumaPolicy::Interleave, MPOL_INTERLEAVE),
+        ];
+        for (policy, mode) in policies.iter() {
+            let ptr = mmap_without_fd(size);
+            if !bind_to_node(ptr, size, 0, *policy) {
+                println!("NUMA policy is not supported");
+                munmap_memory(ptr, size);
+                return;
+            }
+            let (actual_mode, nodes) = memory_policy_of(ptr).unwrap();
+            assert_eq!(actual_mode, *mode);
+            assert!(nodes.contains(&0));
+            unsafe {
+                libc::memset(ptr, 1, size);
+            }
+            assert_eq!(node_of_page(ptr), Some(0));
+            munmap_memory(ptr, size);
+        }
+    }
 }
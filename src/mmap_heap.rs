@@ -1,5 +1,5 @@
 use crate::mmap::{mmap_without_fd, munmap_memory};
-use crate::Ptr;
+use crate::{Ptr, NULL_PTR};
 use core::alloc::{Alloc, AllocErr, Layout};
 use core::ptr;
 
@@ -9,7 +9,9 @@ pub struct MmapAllocator;
 unsafe impl Alloc for MmapAllocator {
     unsafe fn alloc(&mut self, layout: Layout) -> Result<ptr::NonNull<u8>, AllocErr> {
         let addr = mmap_without_fd(layout.size());
-        debug_assert_ne!(addr as usize, 0);
+        if addr == NULL_PTR {
+            return Err(AllocErr);
+        }
         Ok(ptr::NonNull::new(addr as *mut u8).unwrap())
     }
 
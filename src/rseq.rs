@@ -0,0 +1,148 @@
+// CPU running current thread from Linux restartable sequences
+// The kernel updates `cpu_id` of the registered rseq area on every preemption and migration,
+// so reading current CPU is one load instead of a system call. Per-CPU structures are
+// lock-free, a migration right after the read only costs locality and no restart is needed.
+// glibc 2.35+ registers its own area for each thread, which is used instead when present.
+// Only x86_64 is supported, finding the glibc area needs the thread pointer layout of the
+// architecture, and registering another area fails when glibc has one. Elsewhere `cpu_id` is
+// always None and callers fall back to sched_getcpu.
+
+#![cfg_attr(not(target_arch = "x86_64"), allow(dead_code, unused_imports))]
+
+use core::cell::{Cell, UnsafeCell};
+use core::mem;
+use core::ptr;
+
+#[cfg(target_arch = "x86_64")]
+const SYS_RSEQ: libc::c_long = 334;
+const RSEQ_SIG: u32 = 0x5305_3053;
+// Registration is not tried yet
+const UNREGISTERED: usize = 0;
+// No rseq area for the thread, callers fall back to sched_getcpu
+const UNAVAILABLE: usize = 1;
+
+// struct rseq from linux/rseq.h
+#[repr(C, align(32))]
+struct Rseq {
+    cpu_id_start: u32,
+    cpu_id: u32,
+    rseq_cs: u64,
+    flags: u32,
+}
+
+struct ThreadRseq {
+    area: UnsafeCell<Rseq>,
+    // Address of `cpu_id` in the area the kernel updates
+    cpu_id_addr: Cell<usize>,
+}
+
+thread_local! {
+    static THREAD_RSEQ: ThreadRseq = ThreadRseq {
+        area: UnsafeCell::new(Rseq {
+            cpu_id_start: 0,
+            cpu_id: u32::max_value(),
+            rseq_cs: 0,
+            flags: 0,
+        }),
+        cpu_id_addr: Cell::new(UNREGISTERED),
+    }
+}
+
+lazy_static! {
+    // Offset of the area registered by glibc from the thread pointer
+    #[cfg(target_arch = "x86_64")]
+    static ref GLIBC_RSEQ_OFFSET: Option<isize> = glibc_rseq_offset();
+}
+
+// Current CPU, None when rseq is not available for the thread
+#[cfg(target_arch = "x86_64")]
+#[inline]
+pub fn cpu_id() -> Option<u16> {
+    THREAD_RSEQ
+        .try_with(|rseq| {
+            let mut addr = rseq.cpu_id_addr.get();
+            if addr == UNREGISTERED {
+                addr = rseq.register();
+                rseq.cpu_id_addr.set(addr);
+            }
+            if addr == UNAVAILABLE {
+                return None;
+            }
+            // negative when registration is pending or failed
+            let cpu = unsafe { ptr::read_volatile(addr as *const u32) } as i32;
+            if cpu < 0 {
+                None
+            } else {
+                Some(cpu as u16)
+            }
+        })
+        .unwrap_or(None)
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+pub fn cpu_id() -> Option<u16> {
+    None
+}
+
+#[cfg(target_arch = "x86_64")]
+impl ThreadRseq {
+    // Returns the address of `cpu_id` to read, or UNAVAILABLE
+    fn register(&self) -> usize {
+        // only one area can be registered for a thread
+        if let Some(offset) = *GLIBC_RSEQ_OFFSET {
+            let area = (thread_pointer() as isize + offset) as usize;
+            return area + mem::size_of::<u32>();
+        }
+        let area = self.area.get();
+        let len = mem::size_of::<Rseq>() as u32;
+        if unsafe { libc::syscall(SYS_RSEQ, area, len, 0, RSEQ_SIG) } == 0 {
+            unsafe { &(*area).cpu_id as *const u32 as usize }
+        } else {
+            UNAVAILABLE
+        }
+    }
+}
+
+// glibc exports the offset and size of its rseq area, size is 0 when it did not register
+#[cfg(target_arch = "x86_64")]
+fn glibc_rseq_offset() -> Option<isize> {
+    unsafe {
+        let offset = libc::dlsym(libc::RTLD_DEFAULT, b"__rseq_offset\0".as_ptr() as *const _);
+        let size = libc::dlsym(libc::RTLD_DEFAULT, b"__rseq_size\0".as_ptr() as *const _);
+        if offset.is_null() || size.is_null() || *(size as *const u32) == 0 {
+            return None;
+        }
+        Some(*(offset as *const isize))
+    }
+}
+
+// On x86_64 glibc, the thread descriptor is at the thread pointer
+#[cfg(target_arch = "x86_64")]
+fn thread_pointer() -> usize {
+    unsafe { libc::pthread_self() as usize }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::rseq::*;
+
+    #[test]
+    pub fn follows_affinity() {
+        if cpu_id().is_none() {
+            println!("rseq is not available");
+            return;
+        }
+        unsafe {
+            let mut allowed: libc::cpu_set_t = mem::zeroed();
+            let set_size = mem::size_of::<libc::cpu_set_t>();
+            assert_eq!(libc::sched_getaffinity(0, set_size, &mut allowed), 0);
+            for cpu in (0..libc::CPU_SETSIZE as usize).filter(|c| libc::CPU_ISSET(*c, &allowed)) {
+                let mut set: libc::cpu_set_t = mem::zeroed();
+                libc::CPU_SET(cpu, &mut set);
+                assert_eq!(libc::sched_setaffinity(0, set_size, &set), 0);
+                assert_eq!(cpu_id(), Some(cpu as u16));
+            }
+            libc::sched_setaffinity(0, set_size, &allowed);
+        }
+    }
+}
@@ -0,0 +1,229 @@
+// Background scavenger returning free pages to the OS with time decay
+// Free pages are dirty at first. After the dirty decay they are released by MADV_FREE, which the
+// kernel reclaims only under memory pressure, and after the muzzy decay by MADV_DONTNEED.
+// Pages tracked are empty pooled superblocks and free objects spanning whole pages in bump heaps
+// The scavenger is off by default. Without it, expired pooled superblocks are still purged when
+// superblocks are pooled or taken, free pages of bump heaps are kept until `purge`
+// Bump heap objects smaller than a page, and the partial pages at both ends of larger ones, are
+// never returned to the OS. Their pages are only reused by later allocations
+
+use crate::mmap::{dealloc_regional, release_regional};
+use crate::utils::*;
+use crate::{bump_heap, small_heap, Ptr};
+use core::cmp::max;
+use core::sync::atomic::Ordering::{Relaxed, SeqCst};
+use core::sync::atomic::{AtomicBool, AtomicU64};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static DIRTY_DECAY_MS: AtomicU64 = AtomicU64::new(1000);
+static MUZZY_DECAY_MS: AtomicU64 = AtomicU64::new(10000);
+static INTERVAL_MS: AtomicU64 = AtomicU64::new(100);
+
+lazy_static! {
+    // Background scavenger thread, None when it is not running
+    static ref SCAVENGER: Mutex<Option<JoinHandle<()>>> = Mutex::new(None);
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Decay {
+    // Time before free pages are released by MADV_FREE
+    pub dirty_ms: u64,
+    // Time before pages released by MADV_FREE are dropped by MADV_DONTNEED
+    pub muzzy_ms: u64,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Advice {
+    Free,
+    DontNeed,
+}
+
+impl Decay {
+    // Release all free pages at once
+    pub const IMMEDIATE: Decay = Decay {
+        dirty_ms: 0,
+        muzzy_ms: 0,
+    };
+
+    // Advice due for pages free for `elapsed` ms, `muzzy` pages are already MADV_FREE-ed
+    pub fn advice(&self, muzzy: bool, elapsed: u64) -> Option<Advice> {
+        if muzzy {
+            if elapsed >= self.muzzy_ms {
+                Some(Advice::DontNeed)
+            } else {
+                None
+            }
+        } else if elapsed >= self.dirty_ms {
+            if self.muzzy_ms == 0 {
+                Some(Advice::DontNeed)
+            } else {
+                Some(Advice::Free)
+            }
+        } else {
+            None
+        }
+    }
+}
+
+// Apply advice to the whole pages in [addr, addr + size), returns the number of bytes advised
+pub fn release_pages(addr: usize, size: usize, advice: Advice) -> usize {
+    let page_size = *SYS_PAGE_SIZE;
+    let start = addr + align_padding(addr, page_size);
+    let end = (addr + size) & !(page_size - 1);
+    if end <= start {
+        return 0;
+    }
+    match advice {
+        Advice::Free => dealloc_regional(start as Ptr, end - start),
+        Advice::DontNeed => release_regional(start as Ptr, end - start),
+    };
+    end - start
+}
+
+pub fn decay() -> Decay {
+    Decay {
+        dirty_ms: DIRTY_DECAY_MS.load(Relaxed),
+        muzzy_ms: MUZZY_DECAY_MS.load(Relaxed),
+    }
+}
+
+pub fn set_decay(dirty: Duration, muzzy: Duration) {
+    DIRTY_DECAY_MS.store(dirty.as_millis() as u64, Relaxed);
+    MUZZY_DECAY_MS.store(muzzy.as_millis() as u64, Relaxed);
+}
+
+// Time between two scavenger passes
+pub fn set_interval(interval: Duration) {
+    INTERVAL_MS.store(max(interval.as_millis() as u64, 1), Relaxed);
+}
+
+// Least time between purges of superblock pools from allocation paths without the scavenger
+pub fn interval_ms() -> u64 {
+    INTERVAL_MS.load(Relaxed)
+}
+
+// Start the background scavenger thread, noop when it is running
+// Pages of bump heap objects not spanning whole pages are never released, see module docs
+pub fn start() {
+    let mut scavenger = SCAVENGER.lock().unwrap();
+    if scavenger.is_some() {
+        return;
+    }
+    ENABLED.store(true, SeqCst);
+    let spawned = thread::Builder::new()
+        .name("skyhooks-scavenger".to_string())
+        .spawn(run);
+    match spawned {
+        Ok(handle) => *scavenger = Some(handle),
+        Err(e) => {
+            warn!("Cannot start scavenger thread: {}", e);
+            ENABLED.store(false, SeqCst);
+        }
+    }
+}
+
+// Stop the background scavenger and wait for its thread to exit
+// Pages already released stay released
+pub fn stop() {
+    let mut scavenger = SCAVENGER.lock().unwrap();
+    ENABLED.store(false, SeqCst);
+    if let Some(handle) = scavenger.take() {
+        // wake the thread from its sleep between passes
+        handle.thread().unpark();
+        if handle.join().is_err() {
+            warn!("Scavenger thread panicked");
+        }
+    }
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Relaxed)
+}
+
+// Run one pass with current decay, returns the number of bytes released
+pub fn scavenge() -> usize {
+    scavenge_with(monotonic_ms(), decay())
+}
+
+pub fn scavenge_with(now: u64, decay: Decay) -> usize {
+    small_heap::scavenge(now, decay) + bump_heap::scavenge(now, decay)
+}
+
+// Release all free pages regardless of decay, returns the number of bytes released
+pub fn purge() -> usize {
+    small_heap::purge() + bump_heap::scavenge(monotonic_ms(), Decay::IMMEDIATE)
+}
+
+// Runs until `stop` clears the flag, the flag is checked before and after each sleep
+fn run() {
+    while ENABLED.load(SeqCst) {
+        thread::park_timeout(Duration::from_millis(INTERVAL_MS.load(Relaxed)));
+        if !ENABLED.load(SeqCst) {
+            break;
+        }
+        let released = scavenge();
+        if released > 0 {
+            debug!("Scavenger released {} bytes", released);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn advice() {
+        let decay = Decay {
+            dirty_ms: 10,
+            muzzy_ms: 100,
+        };
+        assert_eq!(decay.advice(false, 5), None);
+        assert_eq!(decay.advice(false, 10), Some(Advice::Free));
+        assert_eq!(decay.advice(true, 50), None);
+        assert_eq!(decay.advice(true, 100), Some(Advice::DontNeed));
+        assert_eq!(Decay::IMMEDIATE.advice(false, 0), Some(Advice::DontNeed));
+    }
+
+    #[test]
+    pub fn start_stop() {
+        set_interval(Duration::from_millis(1));
+        for _ in 0..4 {
+            start();
+            start();
+            assert!(is_enabled());
+            thread::sleep(Duration::from_millis(5));
+            stop();
+            assert!(!is_enabled());
+            assert!(SCAVENGER.lock().unwrap().is_none());
+        }
+        set_interval(Duration::from_millis(100));
+    }
+
+    #[test]
+    pub fn purge_all() {
+        unsafe {
+            let size = *crate::small_heap::MAXIMUM_SIZE;
+            let small = (0..64)
+                .map(|_| crate::generic_heap::malloc(size))
+                .collect::<Vec<_>>();
+            let large = crate::generic_heap::malloc(4 * 1024 * 1024);
+            for ptr in small.iter().chain(Some(&large)) {
+                libc::memset(*ptr, 1, size);
+            }
+            for ptr in small.into_iter().chain(Some(large)) {
+                crate::generic_heap::free(ptr);
+            }
+        }
+        assert!(purge() > 0);
+        // released memory can be reused
+        unsafe {
+            let ptr = crate::generic_heap::malloc(4 * 1024 * 1024);
+            libc::memset(ptr, 2, 4 * 1024 * 1024);
+            crate::generic_heap::free(ptr);
+        }
+    }
+}
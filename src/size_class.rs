@@ -0,0 +1,113 @@
+// Size classes shared by small heap and bump heap
+// Four classes in each doubling with 16 bytes quantum, like jemalloc, to bound internal
+// fragmentation by 25%. The class table and lookup table are generated by build.rs
+// With 8 bytes minimum alignment, there is also an 8 bytes tiny class
+
+use core::cmp::max;
+use core::mem;
+
+include!(concat!(env!("OUT_DIR"), "/size_classes.rs"));
+
+// Classes in the first group are quantum spaced
+const FIRST_GROUP_SIZE: usize = QUANTUM * 4;
+const LG_FIRST_GROUP_SIZE: usize = 6;
+
+// Index of the smallest size class fits the size
+// NUM_SIZE_CLASS for sizes beyond the largest class, they are left to direct mapping
+#[inline]
+pub fn size_class_index_from_size(size: usize) -> usize {
+    debug_assert!(size > 0);
+    if size <= LOOKUP_MAX_SIZE {
+        return SIZE_CLASS_LOOKUP[(size + LOOKUP_GRANULE - 1) / LOOKUP_GRANULE] as usize;
+    }
+    if size > SIZE_CLASSES[NUM_SIZE_CLASS - 1] {
+        return NUM_SIZE_CLASS;
+    }
+    // size in (base, base * 2], classes are base + delta * k for k in 1..=4
+    let lg_base = log_2_of(size - 1);
+    let base = 1 << lg_base;
+    let k = ((size - 1 - base) >> (lg_base - 2)) + 1;
+    NUM_TINY_SIZE_CLASS + 4 + (lg_base - LG_FIRST_GROUP_SIZE) * 4 + k - 1
+}
+
+// Index of the smallest size class fits the size, with all objects aligned to `align`
+// Objects are aligned to the largest power of 2 dividing their class size
+#[inline]
+pub fn size_class_index_aligned(size: usize, align: usize) -> usize {
+    let mut index = size_class_index_from_size(max(size, align));
+    while index < NUM_SIZE_CLASS - 1 && SIZE_CLASSES[index] % align != 0 {
+        index += 1;
+    }
+    index
+}
+
+#[inline]
+pub fn size_class_size(index: usize) -> usize {
+    SIZE_CLASSES[index]
+}
+
+#[inline]
+pub fn log_2_of(num: usize) -> usize {
+    mem::size_of::<usize>() * 8 - num.leading_zeros() as usize - 1
+}
+
+#[cfg(test)]
+mod test {
+    use crate::size_class::*;
+
+    #[test]
+    pub fn table() {
+        assert_eq!(
+            &SIZE_CLASSES[NUM_TINY_SIZE_CLASS..][..9],
+            &[16, 32, 48, 64, 80, 96, 112, 128, 160]
+        );
+        assert_eq!(SIZE_CLASSES[NUM_SIZE_CLASS - 1], 1 << 32);
+        for pair in SIZE_CLASSES.windows(2) {
+            assert!(pair[0] < pair[1]);
+            // at most 25% internal fragmentation above the first group
+            if pair[0] >= FIRST_GROUP_SIZE {
+                assert!((pair[1] - pair[0]) * 4 <= pair[1]);
+            }
+        }
+    }
+
+    #[test]
+    pub fn smallest_fit() {
+        let mut index = 0;
+        for size in 1..=(1 << 20) {
+            if SIZE_CLASSES[index] < size {
+                index += 1;
+            }
+            assert_eq!(size_class_index_from_size(size), index, "size {}", size);
+        }
+        for lg in 20..32 {
+            let size = 1 << lg;
+            for size in vec![size - 1, size, size + 1, size + size / 3] {
+                let index = size_class_index_from_size(size);
+                assert!(SIZE_CLASSES[index] >= size);
+                assert!(SIZE_CLASSES[index - 1] < size);
+            }
+        }
+        // no class fits
+        let largest = SIZE_CLASSES[NUM_SIZE_CLASS - 1];
+        assert_eq!(size_class_index_from_size(largest), NUM_SIZE_CLASS - 1);
+        for size in vec![largest + 1, usize::max_value()] {
+            assert_eq!(size_class_index_from_size(size), NUM_SIZE_CLASS);
+        }
+    }
+
+    #[test]
+    pub fn aligned() {
+        assert_eq!(size_class_size(size_class_index_aligned(80, 16)), 80);
+        assert_eq!(size_class_size(size_class_index_aligned(80, 32)), 96);
+        assert_eq!(size_class_size(size_class_index_aligned(80, 64)), 128);
+        assert_eq!(size_class_size(size_class_index_aligned(10, 4096)), 4096);
+        for align in (4..13).map(|i| 1 << i) {
+            for size in 1..20000 {
+                let class_size = size_class_size(size_class_index_aligned(size, align));
+                assert!(class_size >= size);
+                assert_eq!(class_size % align, 0);
+            }
+        }
+    }
+}
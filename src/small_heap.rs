@@ -1,28 +1,66 @@
 use super::*;
 use crate::collections::fixvec::FixedVec;
 use crate::collections::lflist::WordList;
+use crate::collections::segmap::SegmentMap;
 use crate::collections::{evmap, lflist};
-use crate::generic_heap::{log_2_of, size_class_index_from_size, ObjectMeta, NUM_SIZE_CLASS};
+use crate::generic_heap::ObjectMeta;
+use crate::scavenger::{self, release_pages, Advice, Decay};
+use crate::size_class::{
+    size_class_index_aligned, size_class_index_from_size, size_class_size, NUM_TINY_SIZE_CLASS,
+};
 use crate::utils::*;
+use core::cmp::{max, min};
 use core::mem;
 use core::mem::MaybeUninit;
 use core::ptr;
-use core::sync::atomic::{AtomicU32, AtomicUsize};
+use core::sync::atomic::{AtomicU16, AtomicU32, AtomicU64, AtomicU8, AtomicUsize};
+use crossbeam::utils::Backoff;
 use crossbeam_queue::SegQueue;
 use lazy_init::Lazy;
-use lfmap::{Map, WordMap};
-use std::alloc::GlobalAlloc;
+use std::alloc::{GlobalAlloc, Layout};
 use std::cell::{Cell, RefCell};
 use std::clone::Clone;
 use std::ops::Deref;
-use std::sync::atomic::Ordering::Relaxed;
+use std::sync::atomic::Ordering::{Relaxed, SeqCst};
 use std::sync::Arc;
 use std::thread;
 use smallvec::SmallVec;
 
+// Size classes up to 64KB
+const NUM_SIZE_CLASS: usize = NUM_TINY_SIZE_CLASS + 44;
+
 type TSizeClasses = [SizeClass; NUM_SIZE_CLASS];
 type PerNodeMeta = SmallVec<[LazyWrapper<NodeMeta>; 4]>;
 type PerCPUMeta = SmallVec<[LazyWrapper<CoreMeta>; 64]>;
+type Magazines = [Magazine; NUM_SIZE_CLASS];
+
+// Superblock states, only active superblocks in per-CPU size classes can allocate
+const SUPERBLOCK_ACTIVE: u8 = 0;
+// Empty superblock in the per-node pool
+const SUPERBLOCK_POOLED: u8 = 1;
+const SUPERBLOCK_PURGING: u8 = 2;
+// Pooled superblock with its pages released by MADV_FREE
+const SUPERBLOCK_PURGED: u8 = 3;
+// Pooled superblock with its pages dropped by MADV_DONTNEED
+const SUPERBLOCK_RELEASED: u8 = 4;
+
+// Number of objects superblocks are sized for, small classes still take a whole page
+const SUPERBLOCK_TARGET_OBJECTS: usize = 64;
+// Space at superblock tail cannot fit an object is at most 1/16 of the superblock
+const SUPERBLOCK_WASTE_DIVISOR: usize = 16;
+
+// Objects each thread cache magazine refills and flushes at once, 0 disables thread cache
+static CACHE_BATCH: AtomicUsize = AtomicUsize::new(32);
+// Bytes each magazine refills and flushes at once, bounds batches of large size classes
+static CACHE_BATCH_BYTES: AtomicUsize = AtomicUsize::new(64 * 1024);
+// Most objects a magazine refills and flushes at once, `set_cache_batch` is clamped to it
+const MAX_CACHE_BATCH: usize = 64;
+// Magazines hold up to two batches before flushing one
+const MAGAZINE_CAPACITY: usize = MAX_CACHE_BATCH << 1;
+// Objects in magazines never used are tagged by the lowest bit
+const FRESH_TAG: usize = 1;
+// Allocations between sched_getcpu calls when rseq is not available
+const CPU_RECHECK_INTERVAL: u32 = 64;
 
 thread_local! {
     static THREAD_META: ThreadMeta = ThreadMeta::new()
@@ -31,32 +69,60 @@ thread_local! {
 lazy_static! {
     static ref PER_NODE_META: PerNodeMeta = gen_numa_node_list();
     static ref PER_CPU_META: PerCPUMeta = gen_core_meta();
-    static ref SUPERBLOCK_SIZE: usize = *MAXIMUM_SIZE << 2;
+    // Largest superblock, each size class has its own, see `superblock_size`
+    static ref SUPERBLOCK_SIZE: usize = *MAXIMUM_SIZE << 4;
+    // Superblock chunks are aligned to their size, masking an object address gives the chunk
+    // Superblocks are carved from chunks by the size of their class, see `NodeMeta::carve`
+    static ref SUPERBLOCK_CHUNK_SIZE: usize = superblock_chunk_size();
+    // Pages at chunk start with the header offset of the superblock each page of the chunk is in
+    static ref SUPERBLOCK_CHUNK_TABLE_SIZE: usize = chunk_table_size(*SUPERBLOCK_CHUNK_SIZE);
+    // Tags superblock chunks, tells small heap objects apart from others
+    static ref SUPERBLOCK_SEGMENTS: SegmentMap = SegmentMap::new(*SUPERBLOCK_CHUNK_SIZE);
     pub static ref MAXIMUM_SIZE: usize = maximum_size();
 }
 
 #[cfg_attr(target_arch = "x86_64", repr(align(128)))]
 #[cfg_attr(not(target_arch = "x86_64"), repr(align(64)))]
 struct SuperBlock {
-    cpu: u16,
+    // CPU of the per-CPU size class using the superblock, changes when taken from the pool
+    cpu: AtomicU16,
     numa: u16,
     size: u32,
+    tier: u32,
+    // Bytes for objects in the superblock, decided by the size class
+    capacity: u32,
     reservation: AtomicU32,
     used: AtomicU32,
+    state: AtomicU8,
+    // Time of entering current pooled state
+    pooled_at: AtomicU64,
     data_base: usize,
     free_list: lflist::WordList<BumpAllocator>,
 }
 
 struct ThreadMeta {
-    numa: u16,
-    cpu: u16,
+    // CPU last seen running the thread and its node, follow the thread on migration
+    numa: Cell<u16>,
+    cpu: Cell<u16>,
+    // Allocations since last sched_getcpu call
+    ticks: Cell<u32>,
+    // Magazines of objects for each size class, taken and returned without atomics
+    cache: RefCell<Magazines>,
+}
+
+// Fixed capacity stack of cached objects, the buffer is taken from the bump heap on first use
+// Magazines never grow, taking and returning objects never calls back into malloc
+struct Magazine {
+    objects: *mut usize,
+    len: usize,
 }
 
 struct NodeMeta {
     size_class_list: TSizeClasses,
     bump_allocator: bump_heap::AllocatorInstance<BumpAllocator>,
     pending_free: lflist::WordList<BumpAllocator>,
-    objects: lfmap::WordMap<BumpAllocator, AddressHasher>,
+    // Next address to carve superblocks from in current chunk of the node
+    chunk_tail: AtomicUsize,
 }
 
 struct SizeClass {
@@ -66,6 +132,8 @@ struct SizeClass {
     size: u32,
     // SuperBlock ptr address list
     blocks: lflist::WordList<BumpAllocator>,
+    // Time of last purge of per-node pool from release and acquire paths
+    purged_at: AtomicU64,
 }
 
 struct CoreMeta {
@@ -73,53 +141,158 @@ struct CoreMeta {
 }
 
 pub fn allocate(size: usize) -> Ptr {
-    let size_class_index = size_class_index_from_size(size);
-    let max_size = *MAXIMUM_SIZE;
     debug_assert!(size <= *MAXIMUM_SIZE);
-    let (cpu, numa) = THREAD_META.with(|meta| (meta.cpu, meta.numa));
-    let cpu_meta = &PER_CPU_META[cpu as usize];
-    // allocate memory from per-CPU size class list
-    let superblock = &cpu_meta.size_class_list[size_class_index];
-    let (addr, block) = superblock.allocate();
-    debug_assert_eq!(superblock.numa, numa);
-    debug_assert_eq!(unsafe { &*(block as *const SuperBlock) }.numa, numa);
+    allocate_object(size_class_index_from_size(size)).0
+}
+
+// Returns the object address and whether it is never used, which is still zeroed from mmap
+fn allocate_object(size_class_index: usize) -> (Ptr, bool) {
+    let res = THREAD_META
+        .try_with(|meta| meta.allocate(size_class_index))
+        .unwrap_or_else(|_| allocate_without_thread_meta(size_class_index));
+    let (addr, fresh) = match res {
+        Some(res) => res,
+        None => return (NULL_PTR, false),
+    };
     if cfg!(debug_assertions) {
-        debug_check_cache_aligned(addr, size, 8);
-        debug_check_cache_aligned(addr, size, 16);
-        debug_check_cache_aligned(addr, size, 32);
-        debug_check_cache_aligned(addr, size, CACHE_LINE_SIZE);
+        debug_check_aligned(addr, size_class_size(size_class_index));
     }
-    return addr as Ptr;
+    return (addr as Ptr, fresh);
 }
 
-pub fn free(ptr: Ptr) -> bool {
-    let current_numa = THREAD_META.with(|meta| meta.numa);
-    let numa_meta = &PER_NODE_META[current_numa as usize];
-    numa_meta.pending_free.drop_out_all(Some(|(addr, _)| {
-        if let Some(superblock_addr) = numa_meta.objects.get(addr) {
-            let superblock_ref = unsafe { &*(superblock_addr as *const SuperBlock) };
-            superblock_ref.dealloc(addr);
+// Global path for allocations after thread meta is destroyed in TLS destructors
+// Served by the size class of current CPU without thread cache
+fn allocate_without_thread_meta(size_class_index: usize) -> Option<(usize, bool)> {
+    let cpu = current_cpu_fast().unwrap_or_else(current_cpu);
+    let size_class = &PER_CPU_META[cpu as usize].size_class_list[size_class_index];
+    size_class.allocate().map(|(addr, _, fresh)| (addr, fresh))
+}
+
+// Allocate from a core of `node` without thread cache, for threads placing objects on other nodes
+// Objects are freed as usual, through the pending list of `node` when freed from other nodes
+pub fn allocate_on_node(size: usize, align: usize, node: u16) -> Ptr {
+    debug_assert!(align <= *SYS_PAGE_SIZE);
+    // cores of the node the process may run on, any core of the node when none is allowed
+    let usable = usable_node_cpus(node);
+    let cpus: &[u16] = if !usable.is_empty() {
+        &usable
+    } else {
+        match SYS_NODE_CPUS.get(&node) {
+            Some(cpus) if !cpus.is_empty() => cpus,
+            _ => return NULL_PTR,
+        }
+    };
+    // spread threads over cores of the node
+    let current = current_cpu_fast().unwrap_or_else(current_cpu);
+    let cpu = cpus[current as usize % cpus.len()];
+    // objects of the node freed elsewhere are reused first
+    dealloc_pending(node);
+    let size_class_index = size_class_index_aligned(size, align);
+    let size_class = &PER_CPU_META[cpu as usize].size_class_list[size_class_index];
+    debug_assert_eq!(size_class.numa, node);
+    match size_class.allocate() {
+        Some((addr, _, _)) => addr as Ptr,
+        None => NULL_PTR,
+    }
+}
+
+// Bump heap of `node`, its address space is bound to the node
+// Serves objects placed on the node too large for superblocks
+pub fn node_bump_allocator(node: u16) -> &'static bump_heap::AllocatorInstance<BumpAllocator> {
+    &PER_NODE_META[node as usize].bump_allocator
+}
+
+// Bump heap of the node an object from `node_bump_allocator` is in
+pub fn node_bump_allocator_of(
+    ptr: Ptr,
+) -> Option<&'static bump_heap::AllocatorInstance<BumpAllocator>> {
+    PER_NODE_META
+        .iter()
+        .filter_map(|node_meta| node_meta.get())
+        .map(|node_meta| &node_meta.bump_allocator)
+        .find(|allocator| allocator.contains(ptr as *mut u8))
+}
+
+// Objects in superblocks are aligned to the largest power of 2 divides their class size,
+// up to page size. Use the smallest class with size multiple of the alignment
+pub fn allocate_aligned(size: usize, align: usize) -> Ptr {
+    debug_assert!(align <= *SYS_PAGE_SIZE);
+    let ptr = allocate_object(size_class_index_aligned(size, align)).0;
+    debug_assert_eq!(align_padding(ptr as usize, align), 0);
+    ptr
+}
+
+pub fn allocate_zeroed(size: usize, align: usize) -> Ptr {
+    debug_assert!(align <= *SYS_PAGE_SIZE);
+    let (ptr, fresh) = allocate_object(size_class_index_aligned(size, align));
+    if !fresh && ptr != NULL_PTR {
+        unsafe {
+            libc::memset(ptr, 0, size);
         }
-    }));
+    }
+    ptr
+}
+
+pub fn free(ptr: Ptr) -> bool {
     let addr = ptr as usize;
-    if let Some(superblock_addr) = get_from_objects(current_numa, addr) {
+    if let Some(superblock_addr) = superblock_of(addr) {
         let superblock_ref = unsafe { &*(superblock_addr as *const SuperBlock) };
-        if superblock_ref.numa == current_numa {
+        if THREAD_META
+            .try_with(|meta| meta.free(addr, superblock_ref))
+            .is_err()
+        {
+            // thread meta is gone in TLS destructors, object goes back to its superblock
             superblock_ref.dealloc(addr);
-        } else {
-            PER_NODE_META[superblock_ref.numa as usize]
-                .pending_free
-                .push(addr);
         }
         return true;
     } else {
         return false;
     }
 }
+
+// Return object to its superblock, objects from other nodes go to the pending list of their node
+fn dealloc_object(addr: usize, superblock: &SuperBlock, current_numa: u16) {
+    dealloc_pending(current_numa);
+    if superblock.numa == current_numa {
+        superblock.dealloc(addr);
+    } else {
+        PER_NODE_META[superblock.numa as usize]
+            .pending_free
+            .push(addr);
+    }
+}
+
+// Objects freed by threads on other nodes
+fn dealloc_pending(numa: u16) {
+    PER_NODE_META[numa as usize]
+        .pending_free
+        .drop_out_all(Some(|(addr, _)| {
+            if let Some(superblock_addr) = superblock_of(addr) {
+                let superblock_ref = unsafe { &*(superblock_addr as *const SuperBlock) };
+                superblock_ref.dealloc(addr);
+            }
+        }));
+}
+
+// Tune thread cache, magazines refill and flush `objects` at once but no more than `bytes`
+// Zero objects disables thread cache
+// Batches are no more than MAX_CACHE_BATCH objects, magazines have fixed capacity
+pub fn set_cache_batch(objects: usize, bytes: usize) {
+    CACHE_BATCH_BYTES.store(bytes, Relaxed);
+    CACHE_BATCH.store(min(objects, MAX_CACHE_BATCH), Relaxed);
+}
+
+// Objects refilled or flushed at once for a size class, 0 when thread cache is disabled
+#[inline]
+fn cache_batch(class_size: usize) -> usize {
+    let batch = CACHE_BATCH.load(Relaxed);
+    if batch == 0 {
+        return 0;
+    }
+    max(min(batch, CACHE_BATCH_BYTES.load(Relaxed) / class_size), 1)
+}
 pub fn size_of(ptr: Ptr) -> Option<usize> {
-    let addr = ptr as usize;
-    let current_numa = THREAD_META.with(|meta| meta.numa);
-    get_from_objects(current_numa, addr).map(|superblock_addr| {
+    superblock_of(ptr as usize).map(|superblock_addr| {
         let superblock_ref = unsafe { &*(superblock_addr as *const SuperBlock) };
         superblock_ref.size as usize
     })
@@ -131,12 +304,195 @@ impl ThreadMeta {
         let numa_id = numa_from_cpu_id(cpu_id);
         // set_node_affinity(numa_id, tid);
         Self {
-            numa: numa_id,
-            cpu: cpu_id,
+            numa: Cell::new(numa_id),
+            cpu: Cell::new(cpu_id),
+            ticks: Cell::new(0),
+            cache: RefCell::new(magazines()),
+        }
+    }
+
+    // Take object from the magazine, refill it in a batch from per-CPU size class when empty
+    // Returns None only when the system is out of memory
+    fn allocate(&self, size_class_index: usize) -> Option<(usize, bool)> {
+        let cpu = self.serving_cpu();
+        let size_class = &PER_CPU_META[cpu as usize].size_class_list[size_class_index];
+        debug_assert_eq!(size_class.numa, self.numa.get());
+        let batch = cache_batch(size_class.size as usize);
+        if batch == 0 {
+            return size_class.allocate().map(|(addr, _, fresh)| (addr, fresh));
+        }
+        let mut cache = self.cache.borrow_mut();
+        let magazine = &mut cache[size_class_index];
+        if !magazine.reserve() {
+            return size_class.allocate().map(|(addr, _, fresh)| (addr, fresh));
+        }
+        if magazine.is_empty() && !size_class.allocate_batch(batch, magazine) {
+            return None;
+        }
+        magazine
+            .pop()
+            .map(|object| (object & !FRESH_TAG, object & FRESH_TAG != 0))
+    }
+
+    // Keep object from current node in the magazine, flush a batch when it is full
+    fn free(&self, addr: usize, superblock: &SuperBlock) {
+        let batch = cache_batch(superblock.size as usize);
+        if batch == 0 || superblock.numa != self.numa.get() {
+            dealloc_object(addr, superblock, self.numa.get());
+            return;
+        }
+        let mut cache = self.cache.borrow_mut();
+        let magazine = &mut cache[superblock.tier as usize];
+        if !magazine.reserve() {
+            dealloc_object(addr, superblock, self.numa.get());
+            return;
+        }
+        if magazine.len() >= min(batch << 1, MAGAZINE_CAPACITY) {
+            magazine.drain_oldest(batch, |object| self.dealloc_cached(object));
+        }
+        magazine.push(addr);
+    }
+
+    // Return all objects in magazines to their superblocks
+    fn flush(&self) {
+        let mut cache = self.cache.borrow_mut();
+        for magazine in cache.iter_mut() {
+            let len = magazine.len();
+            magazine.drain_oldest(len, |object| self.dealloc_cached(object));
+        }
+    }
+
+    fn dealloc_cached(&self, object: usize) {
+        let addr = object & !FRESH_TAG;
+        let superblock_addr = superblock_of(addr).unwrap();
+        let superblock = unsafe { &*(superblock_addr as *const SuperBlock) };
+        dealloc_object(addr, superblock, self.numa.get());
+    }
+
+    // CPU actually running the thread, from `current_cpu_fast` or sched_getcpu every few allocations
+    #[inline]
+    fn serving_cpu(&self) -> u16 {
+        let cpu = match current_cpu_fast() {
+            Some(cpu) => cpu,
+            None => {
+                let ticks = self.ticks.get().wrapping_add(1);
+                self.ticks.set(ticks);
+                if ticks % CPU_RECHECK_INTERVAL != 0 {
+                    return self.cpu.get();
+                }
+                current_cpu()
+            }
+        };
+        if cpu != self.cpu.get() {
+            self.migrate(cpu);
+        }
+        cpu
+    }
+
+    fn migrate(&self, cpu: u16) {
+        let numa = numa_from_cpu_id(cpu);
+        if numa != self.numa.get() {
+            // cached objects belong to the node the thread left
+            self.flush();
+            self.numa.set(numa);
+        }
+        self.cpu.set(cpu);
+    }
+}
+
+// Thread exit hook, hands thread state back to per-CPU and per-node structures
+// Allocations in later TLS destructors take the global path, see `allocate_without_thread_meta`
+impl Drop for ThreadMeta {
+    fn drop(&mut self) {
+        // objects cached by exiting thread go back for other threads
+        self.flush();
+        // frees from other nodes may wait for threads of this node
+        dealloc_pending(self.numa.get());
+    }
+}
+
+impl Magazine {
+    fn new() -> Self {
+        Self {
+            objects: ptr::null_mut(),
+            len: 0,
+        }
+    }
+
+    // Returns false when there is no memory for the buffer
+    fn reserve(&mut self) -> bool {
+        if self.objects.is_null() {
+            self.objects = unsafe { BumpAllocator.alloc(magazine_layout()) } as *mut usize;
+        }
+        !self.objects.is_null()
+    }
+
+    #[inline]
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    #[inline]
+    fn push(&mut self, object: usize) {
+        debug_assert!(self.len < MAGAZINE_CAPACITY);
+        unsafe {
+            ptr::write(self.objects.add(self.len), object);
+        }
+        self.len += 1;
+    }
+
+    #[inline]
+    fn pop(&mut self) -> Option<usize> {
+        if self.len == 0 {
+            return None;
+        }
+        self.len -= 1;
+        Some(unsafe { ptr::read(self.objects.add(self.len)) })
+    }
+
+    // Take out `count` objects cached the longest, recently freed objects stay for reuse
+    fn drain_oldest<F: FnMut(usize)>(&mut self, count: usize, mut f: F) {
+        let count = min(count, self.len);
+        for i in 0..count {
+            f(unsafe { ptr::read(self.objects.add(i)) });
+        }
+        unsafe {
+            ptr::copy(self.objects.add(count), self.objects, self.len - count);
+        }
+        self.len -= count;
+    }
+}
+
+impl Drop for Magazine {
+    fn drop(&mut self) {
+        debug_assert!(self.is_empty());
+        if !self.objects.is_null() {
+            unsafe { BumpAllocator.dealloc(self.objects as *mut u8, magazine_layout()) }
         }
     }
 }
 
+#[inline]
+fn magazine_layout() -> Layout {
+    let size = MAGAZINE_CAPACITY * mem::size_of::<usize>();
+    Layout::from_size_align(size, CACHE_LINE_SIZE).unwrap()
+}
+
+// Magazines without buffers, creating them allocates nothing
+fn magazines() -> Magazines {
+    let mut data: [MaybeUninit<Magazine>; NUM_SIZE_CLASS] =
+        unsafe { MaybeUninit::uninit().assume_init() };
+    for elem in data.iter_mut() {
+        *elem = MaybeUninit::new(Magazine::new());
+    }
+    unsafe { mem::transmute::<_, Magazines>(data) }
+}
+
 impl SizeClass {
     pub fn new(tier: u32, size: u32, cpu: u16, numa: u16) -> Self {
         debug_assert!(size > 1);
@@ -146,55 +502,202 @@ impl SizeClass {
             numa,
             cpu,
             blocks: WordList::new(),
+            purged_at: AtomicU64::new(0),
         }
     }
 
-    pub fn allocate(&self) -> (usize, usize) {
+    // Returns object address, superblock address and whether the object is never used
+    // Returns None only when the system is out of memory for new superblock
+    pub fn allocate(&self) -> Option<(usize, usize, bool)> {
         // allocate in the superblocks
         loop {
             for (block_addr, _) in self.blocks.iter() {
                 let superblock = unsafe { &*(block_addr as *mut SuperBlock) };
                 debug_assert_eq!(superblock.numa, self.numa);
-                if let Some(addr) = superblock.allocate() {
-                    return (addr, block_addr);
+                if let Some((addr, fresh)) = superblock.allocate() {
+                    return Some((addr, block_addr, fresh));
                 }
             }
-            let new_block = if let Some(numa_common_block) = PER_NODE_META[self.numa as usize]
-                .size_class_list[self.tier as usize]
-                .blocks
-                .pop()
-            {
-                let superblock_ref = unsafe { &mut *(numa_common_block as *mut SuperBlock) };
-                debug_assert_eq!(superblock_ref.numa, self.numa);
-                superblock_ref.cpu = self.cpu;
-                numa_common_block
-            } else {
-                debug_assert!(self.size > 1);
-                SuperBlock::new(self.tier, self.size, self.cpu, self.numa) as usize
-            };
+            let new_block = self.acquire_block()?;
             self.blocks.push(new_block);
         }
     }
+
+    // Take up to `count` objects into `out` for thread cache, fresh objects are tagged
+    // Returns false only when the system is out of memory for new superblock
+    fn allocate_batch(&self, count: usize, out: &mut Magazine) -> bool {
+        debug_assert!(out.is_empty());
+        loop {
+            for (block_addr, _) in self.blocks.iter() {
+                let superblock = unsafe { &*(block_addr as *mut SuperBlock) };
+                superblock.allocate_batch(count - out.len(), out);
+                if out.len() == count {
+                    return true;
+                }
+            }
+            if !out.is_empty() {
+                return true;
+            }
+            match self.acquire_block() {
+                Some(new_block) => self.blocks.push(new_block),
+                None => return false,
+            }
+        }
+    }
+
+    // Take a superblock from the per-node pool or create a new one
+    // Returns None when the system is out of memory
+    fn acquire_block(&self) -> Option<usize> {
+        self.drop_stale_blocks();
+        let pool = &PER_NODE_META[self.numa as usize].size_class_list[self.tier as usize];
+        if let Some(numa_common_block) = pool.blocks.pop() {
+            let superblock_ref = unsafe { &*(numa_common_block as *const SuperBlock) };
+            debug_assert_eq!(superblock_ref.numa, self.numa);
+            superblock_ref.cpu.store(self.cpu, SeqCst);
+            superblock_ref.activate();
+            pool.purge_unattended(monotonic_ms());
+            Some(numa_common_block)
+        } else {
+            debug_assert!(self.size > 1);
+            let block = SuperBlock::new(self.tier, self.size, self.cpu, self.numa);
+            if block.is_null() {
+                None
+            } else {
+                Some(block as usize)
+            }
+        }
+    }
+
+    // Detach the superblock just became empty to the per-node pool for other CPUs
+    // Keep it when it may be the only one of the CPU. Its entry here is left stale, allocations
+    // skip it by the state, see `drop_stale_blocks`. Pooled superblocks expire by the scavenger,
+    // or on release and acquire paths when the scavenger is not running
+    fn release_block(&self, block_addr: usize) {
+        if self.blocks.count() <= 1 {
+            return;
+        }
+        let superblock = unsafe { &*(block_addr as *const SuperBlock) };
+        let now = monotonic_ms();
+        if superblock.try_pool(now) {
+            let pool = &PER_NODE_META[self.numa as usize].size_class_list[self.tier as usize];
+            pool.blocks.push(block_addr);
+            pool.purge_unattended(now);
+        }
+    }
+
+    // Purge expired superblocks in the pool when the scavenger thread is not running
+    fn purge_unattended(&self, now: u64) {
+        if !scavenger::is_enabled() {
+            self.purge_due(now, scavenger::decay());
+        }
+    }
+
+    // Purge expired superblocks at most once per scavenger interval, a single thread at a time
+    fn purge_due(&self, now: u64, decay: Decay) -> usize {
+        let purged_at = self.purged_at.load(Relaxed);
+        if now.saturating_sub(purged_at) < scavenger::interval_ms()
+            || self.purged_at.compare_and_swap(purged_at, now, Relaxed) != purged_at
+        {
+            return 0;
+        }
+        self.purge_expired(now, decay)
+    }
+
+    // Drop entries of superblocks pooled or taken by other CPUs since they were listed here
+    // Superblocks being purged in place are kept, they are active again after purging
+    fn drop_stale_blocks(&self) {
+        let mut blocks = SmallVec::<[usize; 64]>::new();
+        self.blocks
+            .drop_out_all(Some(|(block_addr, _)| blocks.push(block_addr)));
+        // a superblock may be listed again before its stale entry is dropped
+        blocks.sort_unstable();
+        blocks.dedup();
+        for block_addr in blocks {
+            let superblock = unsafe { &*(block_addr as *const SuperBlock) };
+            let state = superblock.state.load(SeqCst);
+            if superblock.cpu.load(SeqCst) == self.cpu
+                && (state == SUPERBLOCK_ACTIVE || state == SUPERBLOCK_PURGING)
+            {
+                self.blocks.push(block_addr);
+            }
+        }
+    }
+
+    // Return pages of superblocks pooled longer than the decay to the OS
+    // Returns number of bytes released
+    fn purge_expired(&self, now: u64, decay: Decay) -> usize {
+        self.blocks
+            .iter()
+            .map(|(block_addr, _)| unsafe { &*(block_addr as *const SuperBlock) })
+            .map(|superblock| superblock.purge(now, decay))
+            .sum()
+    }
+
+    // Release pages of empty superblocks kept in per-CPU size class
+    fn purge_empty_blocks(&self) -> usize {
+        self.blocks
+            .iter()
+            .map(|(block_addr, _)| unsafe { &*(block_addr as *const SuperBlock) })
+            .map(|superblock| superblock.purge_active())
+            .sum()
+    }
+}
+
+impl NodeMeta {
+    // Carve `span` bytes for a superblock from current chunk, returns null when out of memory
+    // A new chunk is taken from the node bump heap when current one cannot fit the span, the rest of
+    // the old chunk is left untouched
+    fn carve(&self, span: usize) -> usize {
+        let chunk_size = *SUPERBLOCK_CHUNK_SIZE;
+        loop {
+            let tail = self.chunk_tail.load(SeqCst);
+            // offset is 0 when current chunk is full
+            let offset = tail & (chunk_size - 1);
+            if offset == 0 || offset + span > chunk_size {
+                break;
+            }
+            if self.chunk_tail.compare_and_swap(tail, tail + span, SeqCst) == tail {
+                map_chunk_pages(tail, span);
+                return tail;
+            }
+        }
+        let tail = self.chunk_tail.load(SeqCst);
+        let chunk = self
+            .bump_allocator
+            .bump_allocate_aligned(chunk_size, chunk_size);
+        if chunk == NULL {
+            return NULL;
+        }
+        SUPERBLOCK_SEGMENTS.insert(chunk);
+        let addr = chunk + *SUPERBLOCK_CHUNK_TABLE_SIZE;
+        // other threads may take new chunks at the same time, later carves go to one of them
+        self.chunk_tail.compare_and_swap(tail, addr + span, SeqCst);
+        map_chunk_pages(addr, span);
+        addr
+    }
 }
 
 impl SuperBlock {
+    // Returns null pointer when out of memory
     pub fn new(tier: u32, size: u32, cpu: u16, numa: u16) -> *mut Self {
         // created a cache aligned super block
         // super block will not deallocated
-        let node_allocator = &PER_NODE_META[numa as usize].bump_allocator;
-        let self_size = mem::size_of::<Self>();
-        let padding = align_padding(self_size, CACHE_LINE_SIZE);
-        // Cache align on data
-        let self_size_with_padding = self_size + padding;
-        let chunk_size = self_size_with_padding + *SUPERBLOCK_SIZE;
-        // use bump_allocate function for it just allocate, do't record object address
-        let addr = node_allocator.bump_allocate(chunk_size);
-        let data_base = addr + self_size_with_padding;
+        let node_meta = &PER_NODE_META[numa as usize];
+        // Align data to the size class for aligned allocations
+        let data_align = data_alignment(size as usize);
+        let capacity = superblock_size(size as usize);
+        let span = superblock_span(capacity, data_align);
+        let addr = node_meta.carve(span);
+        if addr == NULL {
+            return ptr::null_mut();
+        }
+        let data_base = addr + superblock_data_offset(data_align);
         let ptr = addr as *mut Self;
 
-        // ensure cache aligned
-        debug_assert_eq!(align_padding(addr, CACHE_LINE_SIZE), 0);
-        debug_assert_eq!(align_padding(data_base, CACHE_LINE_SIZE), 0);
+        // ensure aligned to page size
+        debug_assert_eq!(align_padding(addr, *SYS_PAGE_SIZE), 0);
+        debug_assert_eq!(align_padding(data_base, data_align), 0);
+        debug_assert!(data_base + capacity <= addr + span);
 
         unsafe {
             ptr::write(
@@ -203,48 +706,232 @@ impl SuperBlock {
                     numa,
                     size,
                     data_base,
-                    cpu,
+                    cpu: AtomicU16::new(cpu),
+                    tier,
+                    capacity: capacity as u32,
                     reservation: AtomicU32::new(0),
                     used: AtomicU32::new(0),
+                    state: AtomicU8::new(SUPERBLOCK_ACTIVE),
+                    pooled_at: AtomicU64::new(0),
                     free_list: lflist::WordList::new(),
                 },
             );
         }
-
         return ptr;
     }
 
-    fn allocate(&self) -> Option<usize> {
-        let res = self.free_list.pop().or_else(|| loop {
-            let pos = self.reservation.load(Relaxed);
-            let pos_ext = pos as usize;
-            if pos_ext as usize >= *SUPERBLOCK_SIZE {
-                return None;
-            } else {
-                let new_pos = pos + self.size;
-                if self.reservation.compare_and_swap(pos, new_pos, Relaxed) == pos {
-                    // insert to per CPU cache to avoid synchronization
-                    let address = pos_ext + self.data_base;
-                    PER_NODE_META[self.numa as usize]
-                        .objects
-                        .insert(address, self as *const Self as usize);
-                    return Some(address);
+    // Objects from reservation are never used, free list objects are dirty
+    fn allocate(&self) -> Option<(usize, bool)> {
+        // count the object before taking it, so purge can see it, see `purge`
+        self.used.fetch_add(self.size, SeqCst);
+        if self.state.load(SeqCst) != SUPERBLOCK_ACTIVE {
+            self.used.fetch_sub(self.size, SeqCst);
+            return None;
+        }
+        let res = self
+            .free_list
+            .pop()
+            .map(|addr| (addr, false))
+            .or_else(|| loop {
+                let pos = self.reservation.load(Relaxed);
+                let pos_ext = pos as usize;
+                if pos + self.size > self.capacity {
+                    return None;
+                } else {
+                    let new_pos = pos + self.size;
+                    if self.reservation.compare_and_swap(pos, new_pos, Relaxed) == pos {
+                        return Some((pos_ext + self.data_base, true));
+                    }
                 }
-            }
-        });
-        if res.is_some() {
-            self.used.fetch_add(self.size, Relaxed);
-            debug_validate(res.unwrap() as Ptr, self.size as usize);
+            });
+        if let Some((addr, _)) = res {
+            debug_validate(addr as Ptr, self.size as usize);
+        } else {
+            self.used.fetch_sub(self.size, Relaxed);
         }
         return res;
     }
 
+    // Take up to `count` objects into `out`, fresh objects from reservation are tagged
+    // All objects are counted at once before taking them, like `allocate`
+    fn allocate_batch(&self, count: usize, out: &mut Magazine) {
+        let size = self.size as usize;
+        let counted = (size * count) as u32;
+        self.used.fetch_add(counted, SeqCst);
+        if self.state.load(SeqCst) != SUPERBLOCK_ACTIVE {
+            self.used.fetch_sub(counted, SeqCst);
+            return;
+        }
+        let mut taken = 0;
+        while taken < count {
+            match self.free_list.pop() {
+                Some(addr) => out.push(addr),
+                None => break,
+            }
+            taken += 1;
+        }
+        while taken < count {
+            let pos = self.reservation.load(Relaxed);
+            let reserve = min(((self.capacity - pos) / self.size) as usize, count - taken);
+            if reserve == 0 {
+                break;
+            }
+            let new_pos = pos + (reserve * size) as u32;
+            if self.reservation.compare_and_swap(pos, new_pos, Relaxed) == pos {
+                let start = self.data_base + pos as usize;
+                (0..reserve).for_each(|i| out.push((start + i * size) | FRESH_TAG));
+                taken += reserve;
+            }
+        }
+        if taken < count {
+            let untaken = ((count - taken) * size) as u32;
+            self.used.fetch_sub(untaken, Relaxed);
+        }
+    }
+
     fn dealloc(&self, addr: usize) {
-        debug_assert!(addr >= self.data_base && addr < self.data_base + *SUPERBLOCK_SIZE);
+        debug_assert!(addr >= self.data_base && addr < self.data_base + self.capacity as usize);
         debug_assert_eq!((addr - self.data_base) % self.size as usize, 0);
         self.free_list.push(addr);
-        self.used.fetch_sub(self.size, Relaxed);
+        let used = self.used.fetch_sub(self.size, SeqCst) - self.size;
+        if used == 0 && self.state.load(Relaxed) == SUPERBLOCK_ACTIVE {
+            let cpu = self.cpu.load(SeqCst);
+            PER_CPU_META[cpu as usize].size_class_list[self.tier as usize]
+                .release_block(self as *const Self as usize);
+        }
     }
+
+    // Mark empty active superblock as pooled, returns false if it is not empty
+    fn try_pool(&self, now: u64) -> bool {
+        if self.used.load(SeqCst) != 0 {
+            return false;
+        }
+        self.pooled_at.store(now, Relaxed);
+        self.state
+            .compare_and_swap(SUPERBLOCK_ACTIVE, SUPERBLOCK_POOLED, SeqCst)
+            == SUPERBLOCK_ACTIVE
+    }
+
+    // Reuse superblock popped from the pool, wait for ongoing purge
+    fn activate(&self) {
+        let backoff = Backoff::new();
+        loop {
+            let state = self.state.load(SeqCst);
+            if state == SUPERBLOCK_ACTIVE {
+                return;
+            } else if state != SUPERBLOCK_PURGING
+                && self
+                    .state
+                    .compare_and_swap(state, SUPERBLOCK_ACTIVE, SeqCst)
+                    == state
+            {
+                return;
+            }
+            backoff.snooze();
+        }
+    }
+
+    // Release pages of empty superblock still active in a per-CPU size class
+    // Allocations are blocked during purging like pooled superblocks, and move on to other blocks
+    fn purge_active(&self) -> usize {
+        if self.used.load(SeqCst) != 0
+            || self
+                .state
+                .compare_and_swap(SUPERBLOCK_ACTIVE, SUPERBLOCK_PURGING, SeqCst)
+                != SUPERBLOCK_ACTIVE
+        {
+            return 0;
+        }
+        let released = if self.used.load(SeqCst) == 0 {
+            let reserved = min(self.reservation.load(Relaxed), self.capacity) as usize;
+            release_pages(self.data_base, reserved, Advice::DontNeed)
+        } else {
+            0
+        };
+        self.state.store(SUPERBLOCK_ACTIVE, SeqCst);
+        released
+    }
+
+    // Release pages of pooled empty superblock to the OS when its decay is due
+    // Returns number of bytes released, pages released by MADV_FREE before are not counted again
+    // Allocations are blocked during purging by the state, see `allocate`
+    fn purge(&self, now: u64, decay: Decay) -> usize {
+        let state = self.state.load(SeqCst);
+        let muzzy = match state {
+            SUPERBLOCK_POOLED => false,
+            SUPERBLOCK_PURGED => true,
+            _ => return 0,
+        };
+        let elapsed = now.saturating_sub(self.pooled_at.load(Relaxed));
+        let advice = match decay.advice(muzzy, elapsed) {
+            Some(advice) => advice,
+            None => return 0,
+        };
+        if self
+            .state
+            .compare_and_swap(state, SUPERBLOCK_PURGING, SeqCst)
+            != state
+        {
+            return 0;
+        }
+        if self.used.load(SeqCst) != 0 {
+            // raced with allocation from stale per-CPU list iteration
+            self.state.store(state, SeqCst);
+            return 0;
+        }
+        // pages beyond reservation are never touched
+        let reserved = min(self.reservation.load(Relaxed), self.capacity) as usize;
+        let released = release_pages(self.data_base, reserved, advice);
+        self.pooled_at.store(now, Relaxed);
+        self.state.store(
+            match advice {
+                Advice::Free => SUPERBLOCK_PURGED,
+                Advice::DontNeed => SUPERBLOCK_RELEASED,
+            },
+            SeqCst,
+        );
+        if muzzy {
+            0
+        } else {
+            released
+        }
+    }
+}
+
+// Purge pooled superblocks and bump heap free lists of initialized nodes
+// Returns number of bytes released
+pub fn scavenge(now: u64, decay: Decay) -> usize {
+    PER_NODE_META
+        .iter()
+        .filter_map(|node_meta| node_meta.get())
+        .map(|node_meta| {
+            node_meta
+                .size_class_list
+                .iter()
+                .map(|size_class| size_class.purge_expired(now, decay))
+                .sum::<usize>()
+                + node_meta.bump_allocator.scavenge(now, decay)
+        })
+        .sum()
+}
+
+// Release pages of every empty superblock, including those kept by CPUs for reuse
+// Returns number of bytes released
+pub fn purge() -> usize {
+    // objects cached by current thread are not free to other threads
+    let _ = THREAD_META.try_with(|meta| meta.flush());
+    let per_cpu: usize = PER_CPU_META
+        .iter()
+        .filter_map(|core_meta| core_meta.get())
+        .map(|core_meta| {
+            core_meta
+                .size_class_list
+                .iter()
+                .map(|size_class| size_class.purge_empty_blocks())
+                .sum::<usize>()
+        })
+        .sum();
+    per_cpu + scavenge(monotonic_ms(), Decay::IMMEDIATE)
 }
 
 fn gen_numa_node_list() -> PerNodeMeta {
@@ -253,9 +940,9 @@ fn gen_numa_node_list() -> PerNodeMeta {
     for i in 0..num_nodes {
         nodes.push(LazyWrapper::new(Box::new(move || NodeMeta {
             size_class_list: size_classes(0, i),
-            bump_allocator: bump_heap::AllocatorInstance::new(),
+            bump_allocator: bump_heap::AllocatorInstance::on_node(i),
             pending_free: lflist::WordList::new(),
-            objects: lfmap::WordMap::with_capacity(*SYS_PAGE_SIZE),
+            chunk_tail: AtomicUsize::new(NULL),
         })));
     }
     return nodes;
@@ -264,16 +951,105 @@ fn gen_numa_node_list() -> PerNodeMeta {
 fn size_classes(cpu: u16, numa: u16) -> TSizeClasses {
     let mut data: [MaybeUninit<SizeClass>; NUM_SIZE_CLASS] =
         unsafe { MaybeUninit::uninit().assume_init() };
-    let mut size = 2;
-    let mut tier = 0;
-    for elem in &mut data[..] {
-        *elem = MaybeUninit::new(SizeClass::new(tier, size, cpu, numa));
-        tier += 1;
-        size <<= 1;
+    for (tier, elem) in data.iter_mut().enumerate() {
+        let size = size_class_size(tier) as u32;
+        *elem = MaybeUninit::new(SizeClass::new(tier as u32, size, cpu, numa));
     }
     unsafe { mem::transmute::<_, TSizeClasses>(data) }
 }
 
+// Bytes for objects in superblocks of a size class, in whole pages for the target number of
+// objects, and grows until the tail cannot fit an object is within the waste bound
+// Per-class sizes and tail waste against the former fixed 256KB, see docs/superblock-sizing.md
+fn superblock_size(class_size: usize) -> usize {
+    let page_size = *SYS_PAGE_SIZE;
+    let max_size = *SUPERBLOCK_SIZE;
+    let target = class_size * SUPERBLOCK_TARGET_OBJECTS;
+    let target_pages = max(target + align_padding(target, page_size), page_size);
+    let mut size = min(target_pages, max_size);
+    while size < max_size && (size % class_size) * SUPERBLOCK_WASTE_DIVISOR > size {
+        size += page_size;
+    }
+    size
+}
+
+// Cache aligned header size
+fn superblock_header_size() -> usize {
+    let self_size = mem::size_of::<SuperBlock>();
+    self_size + align_padding(self_size, CACHE_LINE_SIZE)
+}
+
+// Offset of data from the page aligned header
+fn superblock_data_offset(data_align: usize) -> usize {
+    let header_size = superblock_header_size();
+    header_size + align_padding(header_size, data_align)
+}
+
+// Whole pages a superblock takes in its chunk, pages are never shared by superblocks
+fn superblock_span(capacity: usize, data_align: usize) -> usize {
+    let size = superblock_data_offset(data_align) + capacity;
+    size + align_padding(size, *SYS_PAGE_SIZE)
+}
+
+// Power of 2 fits the page table and the largest superblock with largest alignment
+// Part of the chunk not reserved for objects is never touched
+fn superblock_chunk_size() -> usize {
+    let largest_span = superblock_span(*SUPERBLOCK_SIZE, *SYS_PAGE_SIZE);
+    let mut chunk_size = largest_span.next_power_of_two();
+    while chunk_table_size(chunk_size) + largest_span > chunk_size {
+        chunk_size <<= 1;
+    }
+    chunk_size
+}
+
+// One u32 offset for each page of the chunk, in whole pages
+fn chunk_table_size(chunk_size: usize) -> usize {
+    let page_size = *SYS_PAGE_SIZE;
+    let size = chunk_size / page_size * mem::size_of::<u32>();
+    size + align_padding(size, page_size)
+}
+
+// Point pages of the superblock at `addr` to its header in the table of its chunk
+fn map_chunk_pages(addr: usize, span: usize) {
+    let chunk = addr & !(*SUPERBLOCK_CHUNK_SIZE - 1);
+    let table = chunk as *mut u32;
+    let page_shift = SYS_PAGE_SIZE.trailing_zeros();
+    let first = (addr - chunk) >> page_shift;
+    let last = (addr + span - 1 - chunk) >> page_shift;
+    for page in first..=last {
+        unsafe {
+            ptr::write(table.add(page), (addr - chunk) as u32);
+        }
+    }
+}
+
+// Whether the object is from small heap, without touching it
+#[inline]
+pub fn contains(ptr: Ptr) -> bool {
+    SUPERBLOCK_SEGMENTS.contains(ptr as usize)
+}
+
+// Masking gives the chunk, the table at chunk start gives the superblock of the page
+#[inline]
+fn superblock_of(addr: usize) -> Option<usize> {
+    if SUPERBLOCK_SEGMENTS.contains(addr) {
+        let chunk = addr & !(*SUPERBLOCK_CHUNK_SIZE - 1);
+        let page = (addr - chunk) >> SYS_PAGE_SIZE.trailing_zeros();
+        let offset = unsafe { *(chunk as *const u32).add(page) };
+        Some(chunk + offset as usize)
+    } else {
+        None
+    }
+}
+
+// Alignment of superblock data for a size class, at least cache line size and at most page size
+#[inline]
+fn data_alignment(size: usize) -> usize {
+    // largest power of 2 divides the size
+    let size_align = 1 << size.trailing_zeros();
+    max(min(size_align, *SYS_PAGE_SIZE), CACHE_LINE_SIZE)
+}
+
 fn min_power_of_2(mut n: usize) -> usize {
     let mut count = 0;
     // First n in the below condition
@@ -290,50 +1066,32 @@ fn min_power_of_2(mut n: usize) -> usize {
 
 #[inline]
 fn maximum_size() -> usize {
-    2 << (NUM_SIZE_CLASS - 1)
+    size_class_size(NUM_SIZE_CLASS - 1)
 }
 
 fn gen_core_meta() -> PerCPUMeta {
     let mut vec = PerCPUMeta::new();
     for cpu_id in 0..*NUM_CPU {
         vec.push(LazyWrapper::new(Box::new(move || CoreMeta {
-            size_class_list: size_classes(cpu_id, SYS_CPU_NODE[&cpu_id]),
+            size_class_list: size_classes(cpu_id, numa_from_cpu_id(cpu_id)),
         })));
     }
     return vec;
 }
 
-fn debug_check_cache_aligned(addr: usize, size: usize, align: usize) {
-    if size >= align {
-        // ensure all address are cache aligned
-        debug_assert_eq!(align_padding(addr, align), 0);
-    }
-}
-
-fn get_from_objects(current_numa: u16, addr: usize) -> Option<usize> {
-    let current_numa_ext = current_numa as usize;
-    if let Some(addr) = PER_NODE_META[current_numa_ext].objects.get(addr) {
-        return Some(addr);
-    } else {
-        for (numa_id, numa_meta) in PER_NODE_META
-            .iter()
-            .enumerate()
-            .filter(|(i, _)| i != &current_numa_ext)
-        {
-            if let Some(addr) = PER_NODE_META[numa_id].objects.get(addr) {
-                return Some(addr);
-            }
-        }
-    }
-    return None;
+fn debug_check_aligned(addr: usize, class_size: usize) {
+    // ensure objects are aligned to the largest power of 2 divides their size, up to page size
+    let align = min(1 << class_size.trailing_zeros(), *SYS_PAGE_SIZE);
+    debug_assert_eq!(align_padding(addr, align), 0);
 }
 
 #[cfg(test)]
 mod test {
     use crate::api::SkyhooksAllocator;
-    use crate::small_heap::{allocate, free};
+    use crate::small_heap::*;
     use crate::utils::AddressHasher;
     use lfmap::Map;
+    use std::collections::HashSet;
 
     #[test]
     pub fn general() {
@@ -369,4 +1127,306 @@ mod test {
             assert_eq!(map.remove(i), Some(i * 2), "index: {}", i);
         }
     }
+
+    #[test]
+    pub fn largest_size_class() {
+        assert_eq!(*MAXIMUM_SIZE, 64 * 1024);
+    }
+
+    #[test]
+    pub fn superblock_sizing() {
+        // single superblock size for all classes before sizing by class
+        let fixed_size = 256 * 1024;
+        let page_size = *SYS_PAGE_SIZE;
+        for tier in 0..NUM_SIZE_CLASS {
+            let class_size = size_class_size(tier);
+            let size = superblock_size(class_size);
+            assert_eq!(size % page_size, 0, "class {}", class_size);
+            assert!(size <= *SUPERBLOCK_SIZE, "class {}", class_size);
+            // target number of objects unless bounded by the largest superblock
+            let objects = min(SUPERBLOCK_TARGET_OBJECTS, *SUPERBLOCK_SIZE / class_size);
+            assert!(size / class_size >= objects, "class {}", class_size);
+            // tail cannot fit an object is within the waste bound
+            assert!(
+                size == *SUPERBLOCK_SIZE || (size % class_size) * SUPERBLOCK_WASTE_DIVISOR <= size,
+                "class {}",
+                class_size
+            );
+            // target objects rounded up to whole pages, no more
+            let target = class_size * SUPERBLOCK_TARGET_OBJECTS;
+            assert!(size < target + page_size, "class {}", class_size);
+        }
+        // small classes take a page instead of the fixed size
+        assert_eq!(superblock_size(16), page_size);
+        // the largest class fits 16 objects instead of 4
+        assert_eq!(*MAXIMUM_SIZE * 4, fixed_size);
+        assert!(superblock_size(*MAXIMUM_SIZE) / *MAXIMUM_SIZE >= 16);
+        // superblocks are created with the capacity of their class
+        for size in vec![16, 100, 3000, *MAXIMUM_SIZE] {
+            let ptr = allocate(size);
+            let superblock_addr = superblock_of(ptr as usize).unwrap();
+            let superblock = unsafe { &*(superblock_addr as *const SuperBlock) };
+            let capacity = superblock_size(superblock.size as usize);
+            assert_eq!(superblock.capacity as usize, capacity);
+            assert!(free(ptr));
+        }
+    }
+
+    #[test]
+    pub fn superblock_by_masking() {
+        for size in (3..=16).map(|i| 1 << i).chain(vec![24, 100, 3000, 40000]) {
+            let ptr = allocate(size);
+            assert!(contains(ptr));
+            let superblock_addr = superblock_of(ptr as usize).unwrap();
+            let superblock = unsafe { &*(superblock_addr as *const SuperBlock) };
+            assert!(superblock.size as usize >= size);
+            assert!(ptr as usize >= superblock.data_base);
+            assert!((ptr as usize) < superblock.data_base + superblock.capacity as usize);
+            assert!(free(ptr));
+        }
+        let bump_ptr = unsafe { crate::bump_heap::malloc(64) };
+        assert!(!contains(bump_ptr));
+        unsafe {
+            crate::bump_heap::free(bump_ptr);
+        }
+    }
+
+    #[test]
+    pub fn superblocks_share_chunks() {
+        let chunk_size = *SUPERBLOCK_CHUNK_SIZE;
+        let largest_span = superblock_span(*SUPERBLOCK_SIZE, *SYS_PAGE_SIZE);
+        assert!(chunk_table_size(chunk_size) + largest_span <= chunk_size);
+        // superblocks of small classes take a few pages, not a chunk each
+        let objects = (0..16)
+            .map(|tier| allocate(size_class_size(tier)))
+            .collect::<Vec<_>>();
+        let blocks = objects
+            .iter()
+            .map(|ptr| superblock_of(*ptr as usize).unwrap())
+            .collect::<HashSet<_>>();
+        let chunks = blocks
+            .iter()
+            .map(|addr| addr & !(chunk_size - 1))
+            .collect::<HashSet<_>>();
+        assert_eq!(blocks.len(), objects.len());
+        assert!(chunks.len() < blocks.len());
+        for (tier, ptr) in objects.into_iter().enumerate() {
+            let superblock_addr = superblock_of(ptr as usize).unwrap();
+            let superblock = unsafe { &*(superblock_addr as *const SuperBlock) };
+            assert_eq!(superblock.tier as usize, tier);
+            let data_align = data_alignment(superblock.size as usize);
+            let span = superblock_span(superblock.capacity as usize, data_align);
+            assert!(span < chunk_size / 16);
+            assert!(free(ptr));
+        }
+    }
+
+    #[test]
+    pub fn thread_cache() {
+        let meta = ThreadMeta::new();
+        let tier = size_class_index_from_size(4000);
+        let batch = cache_batch(size_class_size(tier));
+        assert!(batch > 1);
+        let superblock = |addr: usize| {
+            let superblock_addr = superblock_of(addr).unwrap();
+            unsafe { &*(superblock_addr as *const SuperBlock) }
+        };
+        // refilled in a batch
+        let (addr, _) = meta.allocate(tier).unwrap();
+        assert_eq!(meta.cache.borrow()[tier].len(), batch - 1);
+        // freed object is reused at once
+        meta.free(addr, superblock(addr));
+        assert_eq!(meta.allocate(tier).unwrap().0, addr);
+        let objects = (0..batch * 3)
+            .map(|_| meta.allocate(tier).unwrap().0)
+            .collect::<HashSet<_>>();
+        assert_eq!(objects.len(), batch * 3);
+        assert!(!objects.contains(&addr));
+        // magazine is bounded, a batch is flushed when full
+        for addr in objects.into_iter().chain(Some(addr)) {
+            meta.free(addr, superblock(addr));
+            assert!(meta.cache.borrow()[tier].len() <= batch * 2);
+        }
+        // flush returns all objects
+        meta.flush();
+        assert!(meta.cache.borrow().iter().all(|m| m.is_empty()));
+        // thread exit flushes its cache
+        thread::spawn(|| {
+            let objects = (0..100).map(|_| allocate(4000)).collect::<Vec<_>>();
+            for ptr in objects {
+                assert!(free(ptr));
+            }
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    pub fn cross_thread_free() {
+        let tier = size_class_index_from_size(100);
+        let objects = thread::spawn(|| {
+            (0..MAGAZINE_CAPACITY * 4)
+                .map(|_| unsafe { crate::api::nu_malloc(100) } as usize)
+                .collect::<Vec<_>>()
+        })
+        .join()
+        .unwrap();
+        // freed into empty magazines of a thread never allocated, buffers come from bump heap
+        thread::spawn(move || {
+            for addr in &objects {
+                unsafe { crate::api::nu_free(*addr as Ptr) };
+                THREAD_META.with(|meta| {
+                    let cache = meta.cache.try_borrow().unwrap();
+                    assert!(cache[tier].len() <= MAGAZINE_CAPACITY);
+                });
+            }
+            let cached = THREAD_META.with(|meta| meta.cache.borrow()[tier].len());
+            let ptr = unsafe { crate::api::nu_malloc(100) };
+            if cached > 0 {
+                assert!(objects.contains(&(ptr as usize)));
+            }
+            unsafe { crate::api::nu_free(ptr) };
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    pub fn tls_destructor_fallback() {
+        struct LateUser;
+        impl Drop for LateUser {
+            fn drop(&mut self) {
+                // thread meta registered later is destroyed first
+                assert!(THREAD_META.try_with(|_| ()).is_err());
+                let ptr = allocate(100);
+                assert!(!ptr.is_null());
+                assert!(contains(ptr));
+                assert!(free(ptr));
+            }
+        }
+        thread_local! {
+            static LATE_USER: LateUser = LateUser;
+        }
+        thread::spawn(|| {
+            LATE_USER.with(|_| ());
+            let ptr = allocate(100);
+            assert!(free(ptr));
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    pub fn serving_core_follows_thread() {
+        let tier = size_class_index_from_size(3000);
+        let superblock_cpu = |ptr: Ptr| {
+            let superblock_addr = superblock_of(ptr as usize).unwrap();
+            unsafe { (*(superblock_addr as *const SuperBlock)).cpu.load(SeqCst) }
+        };
+        thread::spawn(move || unsafe {
+            let mut allowed: libc::cpu_set_t = mem::zeroed();
+            let set_size = mem::size_of::<libc::cpu_set_t>();
+            assert_eq!(libc::sched_getaffinity(0, set_size, &mut allowed), 0);
+            for cpu in (0..libc::CPU_SETSIZE as usize).filter(|c| libc::CPU_ISSET(*c, &allowed)) {
+                let mut set: libc::cpu_set_t = mem::zeroed();
+                libc::CPU_SET(cpu, &mut set);
+                assert_eq!(libc::sched_setaffinity(0, set_size, &set), 0);
+                // long enough for the sched_getcpu fallback to notice
+                for _ in 0..CPU_RECHECK_INTERVAL {
+                    assert!(free(allocate(100)));
+                }
+                THREAD_META.with(|meta| {
+                    assert_eq!(meta.cpu.get() as usize, cpu);
+                    assert_eq!(meta.numa.get(), numa_from_cpu_id(cpu as u16));
+                    // empty magazine is refilled from the core running the thread
+                    meta.flush();
+                    let (addr, _) = meta.allocate(tier).unwrap();
+                    assert_eq!(superblock_cpu(addr as Ptr) as usize, cpu);
+                    assert!(PER_CPU_META[cpu].get().is_some());
+                    meta.free(addr, &*(superblock_of(addr).unwrap() as *const SuperBlock));
+                });
+            }
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    pub fn node_targeted() {
+        let node = current_numa();
+        let ptrs = (0..100)
+            .map(|_| allocate_on_node(100, 16, node))
+            .collect::<Vec<_>>();
+        for ptr in &ptrs {
+            let superblock_addr = superblock_of(*ptr as usize).unwrap();
+            let superblock = unsafe { &*(superblock_addr as *const SuperBlock) };
+            assert_eq!(superblock.numa, node);
+            assert!(superblock.size >= 100);
+        }
+        for ptr in ptrs {
+            assert!(free(ptr));
+        }
+        assert!(allocate_on_node(100, 16, *NUM_NUMA_NODES).is_null());
+    }
+
+    #[test]
+    pub fn release_superblocks() {
+        // 16 objects in each superblock of the largest size class
+        let size = *MAXIMUM_SIZE;
+        let objects = (0..1024).map(|_| allocate(size)).collect::<Vec<_>>();
+        let current_numa = THREAD_META.with(|meta| meta.numa.get());
+        let blocks = objects
+            .iter()
+            .map(|ptr| superblock_of(*ptr as usize).unwrap())
+            .collect::<HashSet<_>>();
+        assert!(blocks.len() >= 64);
+        for ptr in objects {
+            assert!(free(ptr));
+        }
+        let superblock = |addr: usize| unsafe { &*(addr as *const SuperBlock) };
+        let pooled = blocks
+            .iter()
+            .filter(|addr| superblock(**addr).state.load(Relaxed) != SUPERBLOCK_ACTIVE)
+            .count();
+        // other tests may be using some of them
+        assert!(pooled >= blocks.len() / 2, "pooled {}", pooled);
+        let tier = size_class_index_from_size(size);
+        let pool = &PER_NODE_META[current_numa as usize].size_class_list[tier];
+        let decay = Decay {
+            dirty_ms: 0,
+            muzzy_ms: u64::max_value(),
+        };
+        let released = pool.purge_expired(monotonic_ms(), decay);
+        assert!(released > 0);
+        assert!(blocks
+            .iter()
+            .any(|addr| superblock(*addr).state.load(Relaxed) == SUPERBLOCK_PURGED));
+        // purged superblocks can be reused
+        let ptr = allocate(size);
+        unsafe {
+            libc::memset(ptr, 255, size);
+        }
+        assert!(free(ptr));
+    }
+
+    #[test]
+    pub fn purge_due_pool() {
+        let size = *MAXIMUM_SIZE / 2;
+        let objects = (0..1024).map(|_| allocate(size)).collect::<Vec<_>>();
+        let current_numa = THREAD_META.with(|meta| meta.numa.get());
+        for ptr in objects {
+            assert!(free(ptr));
+        }
+        let tier = size_class_index_from_size(size);
+        let pool = &PER_NODE_META[current_numa as usize].size_class_list[tier];
+        let decay = Decay {
+            dirty_ms: 0,
+            muzzy_ms: u64::max_value(),
+        };
+        // release path purged at most a while ago, the next purge is due
+        let now = monotonic_ms() + scavenger::interval_ms();
+        assert!(pool.purge_due(now, decay) > 0);
+        // at most once per scavenger interval
+        assert_eq!(pool.purge_due(now, decay), 0);
+    }
 }
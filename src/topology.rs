@@ -0,0 +1,367 @@
+// Sources of CPU and NUMA node layout
+// Read from sysfs by default. SKYHOOKS_SYSFS_ROOT points to another sysfs tree, and
+// SKYHOOKS_FAKE_TOPOLOGY=<nodes>x<cpus> makes up a layout, e.g. 4x8 for 4 nodes of 8 CPUs,
+// so cross-node paths can be exercised on single node machines.
+// Threads can also pretend to run on a CPU by `set_thread_cpu`.
+// CPU ids can be sparse, e.g. 0-3 and 8-11, and the process may only run on some of them
+// due to offline CPUs, affinity mask or cgroup cpuset.
+
+use crate::utils::{current_numa, NUM_NUMA_NODES};
+use regex::Regex;
+use smallvec::SmallVec;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, read_dir};
+use std::mem;
+use std::path::PathBuf;
+
+pub type NodeCPUsVec = SmallVec<[u16; 64]>;
+
+const DEFAULT_SYSFS_ROOT: &str = "/sys";
+const SYSFS_ROOT_ENV: &str = "SKYHOOKS_SYSFS_ROOT";
+const FAKE_TOPOLOGY_ENV: &str = "SKYHOOKS_FAKE_TOPOLOGY";
+
+thread_local! {
+    static CPU_OVERRIDE: Cell<Option<u16>> = Cell::new(None)
+}
+
+pub trait TopologyProvider {
+    // CPU ids of each NUMA node
+    fn node_cpus(&self) -> HashMap<u16, NodeCPUsVec>;
+    // Ids of CPUs may ever be online, per-CPU structures are indexed up to the highest
+    fn possible_cpus(&self) -> Vec<u16>;
+    // CPUs current process may run on, None when not restricted
+    fn allowed_cpus(&self) -> Option<Vec<u16>>;
+}
+
+pub struct SysfsTopology {
+    root: PathBuf,
+}
+
+// Nodes with consecutive CPU ids, node `n` has CPUs from `n * cpus_per_node`
+pub struct FakeTopology {
+    nodes: u16,
+    cpus_per_node: u16,
+}
+
+impl SysfsTopology {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn is_host(&self) -> bool {
+        self.root == PathBuf::from(DEFAULT_SYSFS_ROOT)
+    }
+
+    // Effective cpuset of the cgroup of current process, v2 or v1 hierarchy
+    fn cgroup_cpuset(&self) -> Option<Vec<u16>> {
+        let cgroups = fs::read_to_string("/proc/self/cgroup").ok()?;
+        for line in cgroups.lines() {
+            let mut fields = line.splitn(3, ':');
+            let (id, controllers, path) = (fields.next()?, fields.next()?, fields.next()?);
+            let path = path.trim_start_matches('/');
+            let file = if id == "0" && controllers.is_empty() {
+                self.root
+                    .join("fs/cgroup")
+                    .join(path)
+                    .join("cpuset.cpus.effective")
+            } else if controllers.split(',').any(|c| c == "cpuset") {
+                self.root
+                    .join("fs/cgroup/cpuset")
+                    .join(path)
+                    .join("cpuset.effective_cpus")
+            } else {
+                continue;
+            };
+            if let Some(cpus) = read_cpu_list(file) {
+                return Some(cpus);
+            }
+        }
+        None
+    }
+}
+
+impl TopologyProvider for SysfsTopology {
+    fn node_cpus(&self) -> HashMap<u16, NodeCPUsVec> {
+        let node_regex = Regex::new(r"node[0-9]*$").unwrap();
+        let cpu_regex = Regex::new(r"cpu[0-9]*$").unwrap();
+        let number_regex = Regex::new(r"\d+").unwrap();
+        match read_dir(self.root.join("devices/system/node")) {
+            Ok(dir) => dir
+                .filter_map(|entry| {
+                    entry.ok().and_then(|e| {
+                        let p = e.path();
+                        if p.is_dir() {
+                            p.file_name()
+                                .and_then(|os_str| os_str.to_str().map(|s| (s.to_string(), e)))
+                        } else {
+                            None
+                        }
+                    })
+                })
+                .filter(|(file_name, _)| node_regex.is_match(file_name))
+                .map(|(node_name, de)| {
+                    let node_num = number_regex.captures_iter(&node_name).next().unwrap()[0]
+                        .parse::<u16>()
+                        .unwrap();
+                    let path = de.path();
+                    let node_dir = read_dir(path).unwrap();
+                    let cpus = node_dir
+                        .filter_map(|f| f.ok().map(|f| f.file_name().to_str().unwrap().to_string()))
+                        .filter(|f| cpu_regex.is_match(f))
+                        .map(|f| {
+                            let id = number_regex.captures_iter(&f).next().unwrap()[0]
+                                .parse::<u16>()
+                                .unwrap();
+                            id
+                        })
+                        .collect::<NodeCPUsVec>();
+                    (node_num, cpus)
+                })
+                .collect(),
+            Err(_) => vec![(0, (0..num_cpus::get() as u16).collect())]
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    fn possible_cpus(&self) -> Vec<u16> {
+        match read_cpu_list(self.root.join("devices/system/cpu/possible")) {
+            Some(cpus) => cpus,
+            None => {
+                let node_cpus = self.node_cpus();
+                let mut cpus = node_cpus.values().flatten().cloned().collect::<Vec<_>>();
+                cpus.sort();
+                cpus
+            }
+        }
+    }
+
+    fn allowed_cpus(&self) -> Option<Vec<u16>> {
+        // affinity mask of current process only describes the host
+        let affinity = if self.is_host() {
+            affinity_cpus()
+        } else {
+            None
+        };
+        match (affinity, self.cgroup_cpuset()) {
+            (Some(affinity), Some(cpuset)) => Some(
+                affinity
+                    .into_iter()
+                    .filter(|c| cpuset.contains(c))
+                    .collect(),
+            ),
+            (affinity, cpuset) => affinity.or(cpuset),
+        }
+    }
+}
+
+impl FakeTopology {
+    pub fn new(nodes: u16, cpus_per_node: u16) -> Self {
+        assert!(nodes > 0 && cpus_per_node > 0);
+        Self {
+            nodes,
+            cpus_per_node,
+        }
+    }
+
+    // Parse layout like `4x8`
+    pub fn parse(layout: &str) -> Option<Self> {
+        let mut parts = layout.trim().splitn(2, |c| c == 'x' || c == 'X');
+        let nodes = parts.next()?.trim().parse::<u16>().ok()?;
+        let cpus_per_node = parts.next()?.trim().parse::<u16>().ok()?;
+        if nodes == 0 || cpus_per_node == 0 {
+            return None;
+        }
+        Some(Self::new(nodes, cpus_per_node))
+    }
+}
+
+impl TopologyProvider for FakeTopology {
+    fn node_cpus(&self) -> HashMap<u16, NodeCPUsVec> {
+        (0..self.nodes)
+            .map(|node| {
+                let first = node * self.cpus_per_node;
+                (node, (first..first + self.cpus_per_node).collect())
+            })
+            .collect()
+    }
+
+    fn possible_cpus(&self) -> Vec<u16> {
+        (0..self.nodes * self.cpus_per_node).collect()
+    }
+
+    fn allowed_cpus(&self) -> Option<Vec<u16>> {
+        None
+    }
+}
+
+// CPUs the process can run on, sorted. All possible CPUs if the restrictions leave none
+pub fn usable_cpus(provider: &dyn TopologyProvider) -> NodeCPUsVec {
+    let possible = provider.possible_cpus();
+    let mut cpus = match provider.allowed_cpus() {
+        Some(allowed) => possible
+            .iter()
+            .filter(|c| allowed.contains(c))
+            .cloned()
+            .collect::<NodeCPUsVec>(),
+        None => possible.iter().cloned().collect(),
+    };
+    if cpus.is_empty() {
+        warn!("No usable CPU in {:?}, using all possible CPUs", possible);
+        cpus = possible.into_iter().collect();
+    }
+    cpus.sort();
+    cpus
+}
+
+// Parse CPU list format of sysfs and cgroup, like `0-3,8-11`
+// Returns None for malformed or empty list
+pub fn parse_cpu_list(list: &str) -> Option<Vec<u16>> {
+    let mut cpus = Vec::new();
+    for range in list.trim().split(',').filter(|r| !r.is_empty()) {
+        let mut bounds = range.splitn(2, '-');
+        let first = bounds.next()?.trim().parse::<u16>().ok()?;
+        let last = match bounds.next() {
+            Some(last) => last.trim().parse::<u16>().ok()?,
+            None => first,
+        };
+        if last < first {
+            return None;
+        }
+        cpus.extend(first..=last);
+    }
+    if cpus.is_empty() {
+        None
+    } else {
+        Some(cpus)
+    }
+}
+
+fn read_cpu_list(path: PathBuf) -> Option<Vec<u16>> {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|list| parse_cpu_list(&list))
+}
+
+#[cfg(target_os = "linux")]
+fn affinity_cpus() -> Option<Vec<u16>> {
+    unsafe {
+        let mut set: libc::cpu_set_t = mem::zeroed();
+        if libc::sched_getaffinity(0, mem::size_of::<libc::cpu_set_t>(), &mut set) != 0 {
+            return None;
+        }
+        let cpus = (0..libc::CPU_SETSIZE as usize)
+            .filter(|cpu| libc::CPU_ISSET(*cpu, &set))
+            .map(|cpu| cpu as u16)
+            .collect();
+        Some(cpus)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn affinity_cpus() -> Option<Vec<u16>> {
+    None
+}
+
+// Provider chosen by environment variables
+pub fn provider() -> Box<dyn TopologyProvider> {
+    if let Ok(layout) = env::var(FAKE_TOPOLOGY_ENV) {
+        match FakeTopology::parse(&layout) {
+            Some(fake) => return Box::new(fake),
+            None => warn!("Invalid {} {}, using sysfs", FAKE_TOPOLOGY_ENV, layout),
+        }
+    }
+    let root = env::var_os(SYSFS_ROOT_ENV).unwrap_or_else(|| DEFAULT_SYSFS_ROOT.into());
+    Box::new(SysfsTopology::new(root))
+}
+
+// Make current thread act as running on `cpu` regardless of the scheduler, None to undo
+pub fn set_thread_cpu(cpu: Option<u16>) {
+    CPU_OVERRIDE.with(|c| c.set(cpu));
+}
+
+#[inline]
+pub fn thread_cpu() -> Option<u16> {
+    CPU_OVERRIDE.try_with(|c| c.get()).unwrap_or(None)
+}
+
+// Number of NUMA nodes the allocator keeps heaps for
+pub fn num_nodes() -> u16 {
+    *NUM_NUMA_NODES
+}
+
+// Node of the CPU current thread runs on, or pretends to
+pub fn current_node() -> u16 {
+    current_numa()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::topology::*;
+
+    #[test]
+    pub fn fake() {
+        assert!(FakeTopology::parse("4").is_none());
+        assert!(FakeTopology::parse("0x8").is_none());
+        let node_cpus = FakeTopology::parse("4x8").unwrap().node_cpus();
+        assert_eq!(node_cpus.len(), 4);
+        assert_eq!(&node_cpus[&1][..], &[8, 9, 10, 11, 12, 13, 14, 15]);
+        let usable = usable_cpus(&FakeTopology::new(2, 2));
+        assert_eq!(&usable[..], &[0, 1, 2, 3]);
+    }
+
+    #[test]
+    pub fn cpu_list() {
+        let sparse = vec![0, 1, 2, 3, 8, 9, 10, 11];
+        assert_eq!(parse_cpu_list("0-3,8-11\n"), Some(sparse));
+        assert_eq!(parse_cpu_list("5"), Some(vec![5]));
+        assert_eq!(parse_cpu_list("0,2-3"), Some(vec![0, 2, 3]));
+        assert_eq!(parse_cpu_list("\n"), None);
+        assert_eq!(parse_cpu_list("3-1"), None);
+        assert_eq!(parse_cpu_list("a-b"), None);
+    }
+
+    #[test]
+    pub fn sysfs_root() {
+        let root = env::temp_dir().join(format!("skyhooks-sysfs-{}", std::process::id()));
+        // sparse ids, 4-7 are offline
+        for (node, cpu) in &[(0, 0), (0, 1), (1, 8), (1, 9)] {
+            let dir = root.join(format!("devices/system/node/node{}/cpu{}", node, cpu));
+            fs::create_dir_all(dir).unwrap();
+        }
+        fs::create_dir_all(root.join("devices/system/cpu")).unwrap();
+        fs::write(root.join("devices/system/cpu/possible"), "0-3,8-11\n").unwrap();
+        let topology = SysfsTopology::new(&root);
+        let node_cpus = topology.node_cpus();
+        let possible = topology.possible_cpus();
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(node_cpus.len(), 2);
+        let mut cpus = node_cpus[&1].clone();
+        cpus.sort();
+        assert_eq!(&cpus[..], &[8, 9]);
+        assert_eq!(possible, vec![0, 1, 2, 3, 8, 9, 10, 11]);
+    }
+
+    #[test]
+    pub fn host_allowed() {
+        let host = SysfsTopology::new(DEFAULT_SYSFS_ROOT);
+        let allowed = host.allowed_cpus().unwrap();
+        assert!(allowed.contains(&(unsafe { libc::sched_getcpu() } as u16)));
+        let usable = usable_cpus(&host);
+        assert!(usable.iter().all(|cpu| allowed.contains(cpu)));
+    }
+
+    #[test]
+    pub fn thread_override() {
+        assert_eq!(thread_cpu(), None);
+        set_thread_cpu(Some(3));
+        assert_eq!(thread_cpu(), Some(3));
+        std::thread::spawn(|| assert_eq!(thread_cpu(), None))
+            .join()
+            .unwrap();
+        set_thread_cpu(None);
+        assert_eq!(thread_cpu(), None);
+    }
+}
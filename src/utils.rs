@@ -1,4 +1,6 @@
 use crate::bump_heap::BumpAllocator;
+use crate::rseq;
+use crate::topology::{self, NodeCPUsVec};
 use crate::{Ptr, Size};
 use alloc::alloc::Global;
 use core::alloc::{Alloc, GlobalAlloc, Layout};
@@ -7,23 +9,25 @@ use core::ptr::NonNull;
 use lazy_init::Lazy;
 use lfmap::hash;
 use libc::{sysconf, _SC_PAGESIZE};
-use regex::Regex;
 use seahash::SeaHasher;
+use std::alloc::handle_alloc_error;
 use std::cmp::min;
 use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
-use std::fs::read_dir;
 use std::hash::Hasher;
 use std::ops::Deref;
-use smallvec::SmallVec;
 use std::fs::File;
 use std::sync::Mutex;
 use std::io::Write;
 use std::{process, env};
 
 pub const CACHE_LINE_SIZE: usize = 64;
+// Minimum alignment of memory from malloc, 16 bytes fits max_align_t on common 64-bit ABIs
+#[cfg(not(feature = "min_align_8"))]
+pub const MIN_ALIGN: usize = 16;
+#[cfg(feature = "min_align_8")]
+pub const MIN_ALIGN: usize = 8;
 pub type CacheLineType = (usize, usize, usize, usize, usize, usize, usize, usize);
-type NodeCPUsVec = SmallVec<[u16; 64]>;
 
 const HASH_MAGIC_NUMBER_1: usize = 67280421310721;
 const HASH_MAGIC_NUMBER_2: usize = 123456789;
@@ -31,10 +35,14 @@ const HASH_MAGIC_NUMBER_3: usize = 362436069;
 
 lazy_static! {
     pub static ref SYS_PAGE_SIZE: usize = unsafe { sysconf(_SC_PAGESIZE) as usize };
-    pub static ref SYS_NODE_CPUS: HashMap<u16, NodeCPUsVec> = node_topology();
+    pub static ref SYS_NODE_CPUS: HashMap<u16, NodeCPUsVec> = topology::provider().node_cpus();
     pub static ref SYS_CPU_NODE: HashMap<u16, u16> = cpu_topology();
+    // CPUs current process can run on, within affinity mask and cgroup cpuset
+    pub static ref SYS_USABLE_CPUS: NodeCPUsVec = topology::usable_cpus(&*topology::provider());
+    // Node ids can be sparse, per-node structures are indexed up to the highest
     pub static ref NUM_NUMA_NODES: u16 = num_numa_nodes();
-    pub static ref NUM_CPU: u16 = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) as u16 };
+    // CPU ids can be sparse, per-CPU structures are indexed up to the highest possible
+    pub static ref NUM_CPU: u16 = num_cpu_ids();
     pub static ref SYS_TOTAL_MEM: usize = total_memory();
     pub static ref LOG_FILE: Mutex<File> = Mutex::new(
         File::create(&format!("skyhooks.{}.log", process::id())).unwrap());
@@ -93,55 +101,28 @@ pub fn cpu_topology() -> HashMap<u16, u16> {
     cpus
 }
 
-pub fn node_topology() -> HashMap<u16, NodeCPUsVec> {
-    let node_regex = Regex::new(r"node[0-9]*$").unwrap();
-    let cpu_regex = Regex::new(r"cpu[0-9]*$").unwrap();
-    let number_regex = Regex::new(r"\d+").unwrap();
-    match read_dir("/sys/devices/system/node") {
-        Ok(dir) => dir
-            .filter_map(|entry| {
-                entry.ok().and_then(|e| {
-                    let p = e.path();
-                    if p.is_dir() {
-                        p.file_name()
-                            .and_then(|os_str| os_str.to_str().map(|s| (s.to_string(), e)))
-                    } else {
-                        None
-                    }
-                })
-            })
-            .filter(|(file_name, _)| node_regex.is_match(file_name))
-            .map(|(node_name, de)| {
-                let node_num = number_regex.captures_iter(&node_name).next().unwrap()[0]
-                    .parse::<u16>()
-                    .unwrap();
-                let path = de.path();
-                let node_dir = read_dir(path).unwrap();
-                let cpus = node_dir
-                    .filter_map(|f| f.ok().map(|f| f.file_name().to_str().unwrap().to_string()))
-                    .filter(|f| cpu_regex.is_match(f))
-                    .map(|f| {
-                        let id = number_regex.captures_iter(&f).next().unwrap()[0]
-                            .parse::<u16>()
-                            .unwrap();
-                        id
-                    })
-                    .map(|cpu_id| cpu_id)
-                    .collect::<NodeCPUsVec>();
-                (node_num, cpus)
-            })
-            .collect(),
-        Err(_) => vec![(0, (0..num_cpus::get() as u16).map(|n| n).collect())]
-            .into_iter()
-            .collect(),
-    }
+pub fn num_numa_nodes() -> u16 {
+    SYS_CPU_NODE.values().max().map_or(1, |node| node + 1)
 }
 
-pub fn num_numa_nodes() -> u16 {
-    let mut vec = SYS_CPU_NODE.iter().map(|(_, v)| *v).collect::<Vec<_>>();
-    vec.sort();
-    vec.dedup();
-    vec.len() as u16
+pub fn num_cpu_ids() -> u16 {
+    let possible = topology::provider().possible_cpus();
+    let known = SYS_CPU_NODE.keys().cloned();
+    let highest = possible.into_iter().chain(known).max();
+    highest.map_or(1, |cpu| cpu + 1)
+}
+
+// Usable CPUs of a node, may be empty
+pub fn usable_node_cpus(node_id: u16) -> NodeCPUsVec {
+    SYS_NODE_CPUS
+        .get(&node_id)
+        .map(|cpus| {
+            cpus.iter()
+                .filter(|cpu| SYS_USABLE_CPUS.contains(cpu))
+                .cloned()
+                .collect()
+        })
+        .unwrap_or_default()
 }
 
 pub fn total_memory() -> usize {
@@ -151,7 +132,52 @@ pub fn total_memory() -> usize {
 
 #[cfg(target_os = "linux")]
 pub fn current_cpu() -> u16 {
-    unsafe { libc::sched_getcpu() as u16 }
+    topology::thread_cpu().unwrap_or_else(|| fit_cpu(unsafe { libc::sched_getcpu() as u16 }))
+}
+
+// Current CPU without system call, from the thread override or rseq
+// None when only `current_cpu` can tell
+#[cfg(target_os = "linux")]
+#[inline]
+pub fn current_cpu_fast() -> Option<u16> {
+    topology::thread_cpu().or_else(|| rseq::cpu_id().map(fit_cpu))
+}
+
+// Real CPU ids wrap around in made up topology with fewer CPUs
+#[cfg(target_os = "linux")]
+#[inline]
+fn fit_cpu(cpu: u16) -> u16 {
+    let num_cpu = *NUM_CPU;
+    if cpu < num_cpu {
+        cpu
+    } else {
+        cpu % num_cpu
+    }
+}
+
+// Coarse monotonic clock in milliseconds, cheap enough for allocator paths
+#[cfg(target_os = "linux")]
+pub fn monotonic_ms() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_MONOTONIC_COARSE, &mut ts);
+    }
+    ts.tv_sec as u64 * 1000 + ts.tv_nsec as u64 / 1_000_000
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn monotonic_ms() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts);
+    }
+    ts.tv_sec as u64 * 1000 + ts.tv_nsec as u64 / 1_000_000
 }
 
 pub fn cpu_id_from_tid(tid: usize) -> u16 {
@@ -160,7 +186,12 @@ pub fn cpu_id_from_tid(tid: usize) -> u16 {
 
 #[cfg(not(target_os = "linux"))]
 pub fn current_cpu() -> u16 {
-    (current_thread_id() % (*NUM_CPU) as usize) as u16
+    topology::thread_cpu().unwrap_or_else(|| (current_thread_id() % (*NUM_CPU) as usize) as u16)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn current_cpu_fast() -> Option<u16> {
+    topology::thread_cpu()
 }
 
 #[cfg(target_os = "linux")]
@@ -188,7 +219,7 @@ pub fn current_numa() -> u16 {
 pub fn set_node_affinity(node_id: u16, thread_id: usize) {
     unsafe {
         let mut set: libc::cpu_set_t = std::mem::zeroed();
-        let cpus = &SYS_NODE_CPUS[&node_id];
+        let cpus = usable_node_cpus(node_id);
         cpus
             .iter()
             .map(|cpu| *cpu)
@@ -219,7 +250,10 @@ pub fn alloc_mem<A: Alloc + Default>(size: usize) -> usize {
     let align = 16;
     let layout = Layout::from_size_align(size, align).unwrap();
     // must be all zeroed
-    unsafe { a.alloc_zeroed(layout) }.unwrap().as_ptr() as usize
+    match unsafe { a.alloc_zeroed(layout) } {
+        Ok(ptr) => ptr.as_ptr() as usize,
+        Err(_) => handle_alloc_error(layout),
+    }
 }
 
 #[inline]
@@ -271,6 +305,11 @@ impl<T: Sync> LazyWrapper<T> {
             init: create,
         }
     }
+
+    // Returns None without initializing
+    pub fn get(&self) -> Option<&T> {
+        self.inner.get()
+    }
 }
 
 impl<T: Sync> Deref for LazyWrapper<T> {
@@ -294,6 +333,7 @@ mod test {
     use rand_xoshiro::Xoroshiro64StarStar;
     use std::alloc::{Global, GlobalAlloc, Layout, System};
     use std::collections::HashMap;
+    use std::ptr;
     use std::sync::atomic::AtomicUsize;
     use std::sync::atomic::Ordering::Relaxed;
     use std::time::Instant;
@@ -306,6 +346,17 @@ mod test {
         }
     }
 
+    #[test]
+    fn cpu_ids_in_range() {
+        let num_cpu = *super::NUM_CPU;
+        assert!(!super::SYS_USABLE_CPUS.is_empty());
+        assert!(super::SYS_USABLE_CPUS.iter().all(|cpu| *cpu < num_cpu));
+        assert!(super::SYS_CPU_NODE.keys().all(|cpu| *cpu < num_cpu));
+        assert!(super::current_cpu() < num_cpu);
+        let num_nodes = *super::NUM_NUMA_NODES;
+        assert!(super::SYS_CPU_NODE.values().all(|node| *node < num_nodes));
+    }
+
     #[test]
     fn numa() {
         let numa = super::current_numa();
@@ -425,6 +476,48 @@ mod test {
         });
     }
 
+    // Simulate Vec push loop with capacity doubling
+    unsafe fn push_loop<F>(allocator: &SkyhooksAllocator, grow: F)
+    where
+        F: Fn(&SkyhooksAllocator, *mut u8, Layout, usize) -> *mut u8,
+    {
+        let mut cap = 4;
+        let mut layout = Layout::from_size_align(cap * 8, 8).unwrap();
+        let mut ptr = allocator.alloc(layout);
+        for i in 0..(64 * 1024) {
+            if i == cap {
+                cap <<= 1;
+                ptr = grow(allocator, ptr, layout, cap * 8);
+                layout = Layout::from_size_align(cap * 8, 8).unwrap();
+            }
+            *(ptr as *mut usize).add(i) = i;
+        }
+        allocator.dealloc(ptr, layout);
+    }
+
+    #[bench]
+    fn vec_push_realloc(b: &mut Bencher) {
+        let allocator = SkyhooksAllocator;
+        b.iter(|| unsafe {
+            push_loop(&allocator, |a, ptr, layout, new_size| {
+                a.realloc(ptr, layout, new_size)
+            });
+        });
+    }
+
+    #[bench]
+    fn vec_push_alloc_copy(b: &mut Bencher) {
+        let allocator = SkyhooksAllocator;
+        b.iter(|| unsafe {
+            push_loop(&allocator, |a, ptr, layout, new_size| {
+                let new_ptr = a.alloc(Layout::from_size_align(new_size, layout.align()).unwrap());
+                ptr::copy_nonoverlapping(ptr, new_ptr, layout.size());
+                a.dealloc(ptr, layout);
+                new_ptr
+            });
+        });
+    }
+
     #[bench]
     fn timing(b: &mut Bencher) {
         let now = Instant::now();
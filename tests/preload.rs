@@ -0,0 +1,192 @@
+// Run a C program with the built library in LD_PRELOAD and check every allocation from it
+// reached Skyhooks. Allocations are traced by the debug log enabled by LOG=1.
+
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+const PROGRAM: &str = r#"
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+extern void *__libc_malloc(size_t size);
+extern void __libc_free(void *ptr);
+extern void *__libc_calloc(size_t nmemb, size_t size);
+extern void *__libc_realloc(void *ptr, size_t size);
+
+int main() {
+    for (size_t i = 0; i < 32; i++) {
+        char *p = malloc(1000 + i);
+        memset(p, 1, 1000 + i);
+        free(p);
+    }
+    for (size_t i = 0; i < 32; i++) {
+        char *p = calloc(1, 2000 + i);
+        if (p[0] != 0) return 1;
+        free(p);
+    }
+    char *p = malloc(16);
+    for (size_t i = 0; i < 32; i++) {
+        p = realloc(p, 3000 + i * 100);
+        memset(p, 2, 3000 + i * 100);
+    }
+    free(p);
+    for (size_t i = 0; i < 32; i++) {
+        char *p = __libc_malloc(5000 + i);
+        memset(p, 3, 5000 + i);
+        __libc_free(p);
+        p = __libc_calloc(1, 6000 + i);
+        p = __libc_realloc(p, 7000 + i);
+        __libc_free(p);
+    }
+    printf("done\n");
+    return 0;
+}
+"#;
+
+// Operator new of the library throws std::bad_alloc after the new-handler loop gives up
+const CXX_PROGRAM: &str = r#"
+#include <cstdio>
+#include <new>
+
+static int handled = 0;
+
+static void handler() {
+    handled++;
+    std::set_new_handler(nullptr);
+}
+
+int main() {
+    for (size_t i = 0; i < 32; i++) {
+        char *p = new char[1000 + i];
+        delete[] p;
+    }
+    size_t huge = size_t(1) << 62;
+    try {
+        char *p = new char[huge];
+        delete[] p;
+        return 1;
+    } catch (const std::bad_alloc &) {
+    }
+    std::set_new_handler(handler);
+    try {
+        char *p = new char[huge];
+        delete[] p;
+        return 2;
+    } catch (const std::bad_alloc &) {
+    }
+    if (handled != 1) return 3;
+    if (new (std::nothrow) char[huge] != nullptr) return 4;
+    printf("done\n");
+    return 0;
+}
+"#;
+
+fn target_dir() -> PathBuf {
+    // target/<profile>/deps/<test binary>
+    let exe = env::current_exe().unwrap();
+    exe.parent().unwrap().parent().unwrap().to_path_buf()
+}
+
+#[test]
+#[cfg_attr(
+    any(not(debug_assertions), not(target_os = "linux")),
+    ignore = "allocation log is only written by debug builds on Linux"
+)]
+fn preload() {
+    let lib = target_dir().join("libskyhooks.so");
+    assert!(lib.exists(), "Cannot find library at {:?}", lib);
+    let work_dir = env::temp_dir().join(format!("skyhooks-preload-{}", std::process::id()));
+    fs::create_dir_all(&work_dir).unwrap();
+    let source = work_dir.join("prog.c");
+    let program = work_dir.join("prog");
+    fs::write(&source, PROGRAM).unwrap();
+    let status = Command::new("cc")
+        .arg(&source)
+        .arg("-o")
+        .arg(&program)
+        .status()
+        .expect("Cannot run C compiler");
+    assert!(status.success());
+
+    let output = Command::new(&program)
+        .current_dir(&work_dir)
+        .env("LD_PRELOAD", &lib)
+        .env("LOG", "1")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "done\n");
+
+    let logged = fs::read_dir(&work_dir)
+        .unwrap()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_name().to_string_lossy().starts_with("skyhooks."))
+        .map(|e| fs::read_to_string(e.path()).unwrap())
+        .collect::<String>();
+    let sizes = logged
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.split(',');
+            let action = parts.next()?.trim();
+            let size = parts.next()?.trim().parse::<usize>().ok()?;
+            // realloc in place allocates nothing, it is logged on its own
+            if action.ends_with("MALLOC") || action == "REALLOC" {
+                Some(size)
+            } else {
+                None
+            }
+        })
+        .collect::<HashSet<_>>();
+    let mut expected = vec![];
+    for i in 0..32 {
+        expected.push(1000 + i);
+        expected.push(2000 + i);
+        expected.push(3000 + i * 100);
+        expected.push(5000 + i);
+        expected.push(6000 + i);
+        expected.push(7000 + i);
+    }
+    for size in expected {
+        assert!(
+            sizes.contains(&size),
+            "allocation of {} bypassed skyhooks",
+            size
+        );
+    }
+    fs::remove_dir_all(&work_dir).unwrap();
+}
+
+#[test]
+#[cfg_attr(
+    not(target_os = "linux"),
+    ignore = "operator new is only exported on Linux"
+)]
+fn preload_cxx() {
+    let lib = target_dir().join("libskyhooks.so");
+    assert!(lib.exists(), "Cannot find library at {:?}", lib);
+    let work_dir = env::temp_dir().join(format!("skyhooks-preload-cxx-{}", std::process::id()));
+    fs::create_dir_all(&work_dir).unwrap();
+    let source = work_dir.join("prog.cc");
+    let program = work_dir.join("prog");
+    fs::write(&source, CXX_PROGRAM).unwrap();
+    let status = Command::new("c++")
+        .arg(&source)
+        .arg("-o")
+        .arg(&program)
+        .status()
+        .expect("Cannot run C++ compiler");
+    assert!(status.success());
+
+    let output = Command::new(&program)
+        .current_dir(&work_dir)
+        .env("LD_PRELOAD", &lib)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "done\n");
+    fs::remove_dir_all(&work_dir).unwrap();
+}
@@ -0,0 +1,42 @@
+// Free a large object and check the background scavenger returns its pages to the OS.
+// In its own test binary so other tests cannot disturb the resident set size.
+
+use std::fs;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Resident pages from /proc/self/statm
+fn resident_pages() -> usize {
+    let statm = fs::read_to_string("/proc/self/statm").unwrap();
+    statm.split_whitespace().nth(1).unwrap().parse().unwrap()
+}
+
+#[test]
+fn rss_drops_after_free() {
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let size = 32 * 1024 * 1024;
+    skyhooks::scavenger::set_decay(Duration::from_millis(0), Duration::from_millis(0));
+    skyhooks::scavenger::set_interval(Duration::from_millis(10));
+    unsafe {
+        let ptr = skyhooks::malloc(size);
+        assert!(!ptr.is_null());
+        libc::memset(ptr, 1, size);
+        let before = resident_pages();
+        skyhooks::free(ptr);
+        skyhooks::scavenger::start();
+        let expected = before - size / page_size / 2;
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut after = resident_pages();
+        while after > expected && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(50));
+            after = resident_pages();
+        }
+        skyhooks::scavenger::stop();
+        assert!(
+            after <= expected,
+            "resident pages before free {}, after {}",
+            before,
+            after
+        );
+    }
+}
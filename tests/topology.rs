@@ -0,0 +1,69 @@
+// Cross-node paths on a simulated 4 nodes x 8 CPUs layout.
+// Topology is read once per process, so the test reruns itself with the layout in environment.
+
+use std::alloc::Layout;
+use std::collections::HashSet;
+use std::env;
+use std::process::Command;
+
+const LAYOUT_ENV: &str = "SKYHOOKS_FAKE_TOPOLOGY";
+
+#[test]
+fn simulated_nodes() {
+    if env::var_os(LAYOUT_ENV).is_none() {
+        let status = Command::new(env::current_exe().unwrap())
+            .env(LAYOUT_ENV, "4x8")
+            .args(&["simulated_nodes", "--exact", "--nocapture"])
+            .status()
+            .unwrap();
+        assert!(status.success());
+        return;
+    }
+    assert_eq!(skyhooks::topology::num_nodes(), 4);
+    // without thread cache, every free takes back objects pending for its node
+    skyhooks::set_thread_cache_batch(0, 0);
+    skyhooks::topology::set_thread_cpu(Some(9));
+    assert_eq!(skyhooks::topology::current_node(), 1);
+    unsafe {
+        let objects = (0..1000)
+            .map(|_| skyhooks::malloc(100) as usize)
+            .collect::<HashSet<_>>();
+        // freed on node 0, objects wait in the pending list of node 1
+        skyhooks::topology::set_thread_cpu(Some(2));
+        assert_eq!(skyhooks::topology::current_node(), 0);
+        for ptr in &objects {
+            skyhooks::free(*ptr as *mut _);
+        }
+        // next free on node 1 takes them back
+        skyhooks::topology::set_thread_cpu(Some(12));
+        skyhooks::free(skyhooks::malloc(100));
+        let reused = (0..1000)
+            .map(|_| skyhooks::malloc(100) as usize)
+            .filter(|ptr| objects.contains(ptr))
+            .count();
+        assert!(reused > 0);
+        // placed on node 3 from node 0, frees go to the pending list of node 3
+        skyhooks::topology::set_thread_cpu(Some(2));
+        let layout = Layout::from_size_align(200, 64).unwrap();
+        let objects = (0..1000)
+            .map(|_| skyhooks::alloc_on_node(layout, 3) as usize)
+            .collect::<HashSet<_>>();
+        assert!(objects.iter().all(|ptr| *ptr != 0 && *ptr % 64 == 0));
+        for ptr in &objects {
+            skyhooks::free(*ptr as *mut _);
+        }
+        let reused = (0..1000)
+            .map(|_| skyhooks::alloc_on_node(layout, 3) as usize)
+            .filter(|ptr| objects.contains(ptr))
+            .count();
+        assert!(reused > 0);
+        assert!(skyhooks::alloc_on_node(layout, 4).is_null());
+        assert_eq!(errno::errno().0, libc::EINVAL);
+        let large = skyhooks::sk_malloc_onnode(8 * 1024 * 1024, 1);
+        assert!(!large.is_null());
+        libc::memset(large, 1, 8 * 1024 * 1024);
+        skyhooks::free(large);
+        assert!(skyhooks::sk_malloc_onnode(100, -1).is_null());
+    }
+    skyhooks::topology::set_thread_cpu(None);
+}